@@ -1,7 +1,12 @@
 use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
+use futures::channel::oneshot;
 use libp2p::PeerId;
 
+use crate::error::FaasError;
+use crate::model::{LoadReport, OpenFaaSResponse, ProviderCacheStats};
+
 use log::info;
 
 #[derive(Debug)]
@@ -24,6 +29,11 @@ impl RequestsInProgress {
         }
     }
 
+    /// Whether there are no requests in progress to any peer.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
     pub fn push_req(&mut self, item: &PeerId, manycall_first_use: bool) -> bool {
         // Check if peer exists in hashmap
         if let Some(data) = self.map.get(item) {
@@ -37,15 +47,14 @@ impl RequestsInProgress {
             // Check if exists queue in new position
             if new_pos >= self.queues_vector.len() {
                 // If if does not exists, create new queue with it
-                let mut new_queue: Vec<PeerId> = Vec::new();
-                new_queue.push(item.clone());
+                let new_queue: Vec<PeerId> = vec![*item];
                 self.queues_vector.push(new_queue);
             }
             else {
                 // If it exists, push item to the queue of that position
-                self.queues_vector[new_pos].push(item.clone());
+                self.queues_vector[new_pos].push(*item);
             }
-            self.map.insert(item.clone(), PeerData{vector_position: new_pos, manycall_in_progress: new_mp});
+            self.map.insert(*item, PeerData{vector_position: new_pos, manycall_in_progress: new_mp});
             info!("Pushed: {:?} from pos: {:?} to pos: {:?}", item, pos, new_pos);
             info!("Actual state");
             info!("{:?}", self);
@@ -53,8 +62,8 @@ impl RequestsInProgress {
         } else {
             // If peer does not exists in hashmap, push it to the first queue
             let new_mp = if manycall_first_use { 1 } else { 0 };
-            self.queues_vector[1].push(item.clone());
-            self.map.insert(item.clone(), PeerData{vector_position: 1, manycall_in_progress: new_mp});
+            self.queues_vector[1].push(*item);
+            self.map.insert(*item, PeerData{vector_position: 1, manycall_in_progress: new_mp});
             info!("Pushed new peer: {:?} to pos: {:?}", item, 1);
             info!("Actual state");
             info!("{:?}", self);
@@ -79,16 +88,16 @@ impl RequestsInProgress {
             };
 
             // Check if new position is different from actual position (when actual is 0)
-            if new_pos > 0 || ( new_pos == 0 && is_manycall ) || ( new_pos == 0 && !is_manycall && mp > 0 ) {
-                self.queues_vector[new_pos].push(item.clone());
-                self.map.insert(item.clone(), PeerData{vector_position: new_pos, manycall_in_progress: mp});
+            if new_pos > 0 || is_manycall || mp > 0 {
+                self.queues_vector[new_pos].push(*item);
+                self.map.insert(*item, PeerData{vector_position: new_pos, manycall_in_progress: mp});
                 info!("Popped: {:?} from pos: {:?} to pos: {:?}", item, pos, new_pos);
             }
             else {
                 self.map.remove(item);
                 info!("Removed: {:?} from pos: {:?}", item, pos);
             }
-            while self.queues_vector.len() > 2 && self.queues_vector.last().map_or(false, |deque| deque.is_empty()) {
+            while self.queues_vector.len() > 2 && self.queues_vector.last().is_some_and(|deque| deque.is_empty()) {
                 self.queues_vector.pop();
             }
             info!("Actual state");
@@ -99,36 +108,31 @@ impl RequestsInProgress {
         }
     }
 
-    pub fn get_peer(&mut self, providers: &HashSet<PeerId>) -> Option<PeerId> {
+    /// Returns the nearest provider among the least loaded ones.
+    pub fn get_peer(&mut self, providers: &HashSet<PeerId>, latencies: &PeerLatencies) -> Option<PeerId> {
         for (index, queue) in self.queues_vector.iter().enumerate() {
-            for item in queue {
-                if providers.contains(item) {
-                    info!("Requested peer...");
-                    info!("Actual state");
-                    info!("{:?}", self);
-                    info!("First peer from providers found: {:?}", item);
-                    let provider = item.clone();
-                    self.queues_vector[index].retain(|x| x != &provider);
-                    return Some(provider);
-                }
-            }
-        }
-        None
-    }
-
-    pub fn find_not_in_progress_peer(&mut self, providers: &HashSet<PeerId>) -> Option<PeerId> {
-        for provider in providers {
-            if self.map.contains_key(provider) == false {
-                info!("Peer with no requests ongoing found: {:?}", provider);
+            if let Some(provider) = latencies.nearest(queue.iter().filter(|item| providers.contains(item))) {
+                info!("Requested peer...");
                 info!("Actual state");
                 info!("{:?}", self);
-                info!("First peer from providers found: {:?}", provider);
-                return Some(provider.clone());
+                info!("Nearest peer from providers found: {:?}", provider);
+                self.queues_vector[index].retain(|x| x != &provider);
+                return Some(provider);
             }
         }
         None
     }
 
+    /// Returns the nearest provider with no requests in progress.
+    pub fn find_not_in_progress_peer(&mut self, providers: &HashSet<PeerId>, latencies: &PeerLatencies) -> Option<PeerId> {
+        let provider = latencies.nearest(providers.iter().filter(|provider| !self.map.contains_key(provider)))?;
+        info!("Peer with no requests ongoing found: {:?}", provider);
+        info!("Actual state");
+        info!("{:?}", self);
+        info!("Nearest peer from providers found: {:?}", provider);
+        Some(provider)
+    }
+
     pub fn remove_manycall(&mut self, providers: &HashSet<PeerId>) {
         for provider in providers {
             if let Some(data) = self.map.get(provider) {
@@ -140,7 +144,7 @@ impl RequestsInProgress {
                     info!("Removed provider {:?} from position {:?}", provider, pos);
                 }
                 else {
-                    self.map.insert(provider.clone(), PeerData{vector_position: pos, manycall_in_progress: mp - 1});
+                    self.map.insert(*provider, PeerData{vector_position: pos, manycall_in_progress: mp - 1});
                     info!("Decreased manycall count for provider {:?} from position {:?}", provider, pos);
                 }
             }
@@ -149,3 +153,410 @@ impl RequestsInProgress {
         info!("{:?}", self);
     }
 }
+
+/// Round-trip times to the connected peers, smoothed with an exponentially weighted moving average.
+#[derive(Debug, Clone, Default)]
+pub struct PeerLatencies {
+    rtts: HashMap<PeerId, Duration>,
+}
+
+impl PeerLatencies {
+    /// Weight of a new measurement in the average.
+    const SMOOTHING: f64 = 0.2;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, peer: PeerId, rtt: Duration) {
+        let average = match self.rtts.get(&peer) {
+            Some(average) => average.mul_f64(1.0 - Self::SMOOTHING) + rtt.mul_f64(Self::SMOOTHING),
+            None => rtt,
+        };
+        self.rtts.insert(peer, average);
+    }
+
+    pub fn remove(&mut self, peer: &PeerId) {
+        self.rtts.remove(peer);
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<Duration> {
+        self.rtts.get(peer).copied()
+    }
+
+    /// Returns the peer with the lowest round-trip time, peers not measured yet come last.
+    pub fn nearest<'a>(&self, peers: impl IntoIterator<Item = &'a PeerId>) -> Option<PeerId> {
+        peers
+            .into_iter()
+            .min_by_key(|peer| self.get(peer).unwrap_or(Duration::MAX))
+            .copied()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &Duration)> {
+        self.rtts.iter()
+    }
+}
+
+/// Latest load reported by each peer, forgotten once it is older than the time to live.
+#[derive(Debug, Clone)]
+pub struct PeerLoads {
+    reports: HashMap<PeerId, (LoadReport, Instant)>,
+    ttl: Duration,
+}
+
+impl PeerLoads {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            reports: HashMap::new(),
+            ttl,
+        }
+    }
+
+    pub fn insert(&mut self, peer: PeerId, report: LoadReport) {
+        self.reports.insert(peer, (report, Instant::now()));
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<&LoadReport> {
+        self.reports
+            .get(peer)
+            .filter(|(_, received_at)| received_at.elapsed() < self.ttl)
+            .map(|(report, _)| report)
+    }
+
+    pub fn is_saturated(&self, peer: &PeerId) -> bool {
+        self.get(peer).is_some_and(LoadReport::is_saturated)
+    }
+
+    /// Drops the providers whose last report says they are saturated, unless all of them are.
+    /// Draining providers are only kept when every provider is draining.
+    pub fn unsaturated(&self, providers: &HashSet<PeerId>) -> HashSet<PeerId> {
+        let not_draining: HashSet<PeerId> = providers
+            .iter()
+            .filter(|provider| !self.get(provider).is_some_and(|report| report.draining))
+            .copied()
+            .collect();
+        let providers = if not_draining.is_empty() { providers } else { &not_draining };
+        let unsaturated: HashSet<PeerId> = providers
+            .iter()
+            .filter(|provider| !self.is_saturated(provider))
+            .copied()
+            .collect();
+        if unsaturated.is_empty() {
+            providers.clone()
+        } else {
+            unsaturated
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &LoadReport)> {
+        self.reports
+            .iter()
+            .filter(|(_, (_, received_at))| received_at.elapsed() < self.ttl)
+            .map(|(peer, (report, _))| (peer, report))
+    }
+
+    /// Returns the unsaturated peers not deploying the function yet, the ones with the fewest
+    /// invocations in progress and then the lowest CPU usage first.
+    pub fn least_loaded_without(&self, function_name: &str) -> Vec<PeerId> {
+        let mut peers: Vec<(&PeerId, &LoadReport)> = self.iter()
+            .filter(|(_, report)| !report.is_saturated() && !report.warm_functions.iter().any(|name| name == function_name))
+            .collect();
+        peers.sort_by(|(_, a), (_, b)| {
+            let (a_in_progress, a_cpu) = a.load();
+            let (b_in_progress, b_cpu) = b.load();
+            a_in_progress.cmp(&b_in_progress).then(a_cpu.total_cmp(&b_cpu))
+        });
+        peers.into_iter().map(|(peer, _)| *peer).collect()
+    }
+}
+
+/// Demand for a function executed by the local node.
+#[derive(Debug, Clone)]
+pub struct Demand {
+    /// Invocations started since the demand was last sampled.
+    pub invocations: u64,
+    /// Invocations in progress.
+    pub in_progress: usize,
+    pub last_invoked: Instant,
+}
+
+/// Demand for each function executed by the local node, to find the functions to replicate
+/// and the replicas no longer used.
+#[derive(Debug, Default)]
+pub struct FunctionDemand {
+    functions: HashMap<String, Demand>,
+}
+
+impl FunctionDemand {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn start(&mut self, function_name: &str) {
+        let demand = self.functions.entry(function_name.to_string()).or_insert_with(|| Demand {
+            invocations: 0,
+            in_progress: 0,
+            last_invoked: Instant::now(),
+        });
+        demand.invocations += 1;
+        demand.in_progress += 1;
+        demand.last_invoked = Instant::now();
+    }
+
+    pub fn finish(&mut self, function_name: &str) {
+        if let Some(demand) = self.functions.get_mut(function_name) {
+            demand.in_progress = demand.in_progress.saturating_sub(1);
+            demand.last_invoked = Instant::now();
+        }
+    }
+
+    /// Returns the demand for every function, starting a new count of invocations.
+    pub fn sample(&mut self) -> HashMap<String, Demand> {
+        let sample = self.functions.clone();
+        for demand in self.functions.values_mut() {
+            demand.invocations = 0;
+        }
+        sample
+    }
+
+    /// Invocations of the function in progress.
+    pub fn in_progress(&self, function_name: &str) -> usize {
+        self.functions.get(function_name).map_or(0, |demand| demand.in_progress)
+    }
+
+    pub fn remove(&mut self, function_name: &str) {
+        self.functions.remove(function_name);
+    }
+}
+
+/// Identifies an invocation so that identical concurrent requests can share one execution.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct InvocationKey {
+    pub name: String,
+    pub method: String,
+    pub body: Option<Vec<u8>>,
+}
+
+/// Result handed to every request waiting on a shared execution.
+pub type SharedInvocationResult = Result<OpenFaaSResponse, FaasError>;
+
+/// Outcome of joining the identical invocations in progress.
+#[derive(Debug)]
+pub enum JoinedInvocation {
+    /// An identical invocation lasting at least as long as the caller is running, its result is sent here.
+    Waiting(oneshot::Receiver<SharedInvocationResult>),
+    /// The caller executes the invocation and shares its result.
+    Leader,
+    /// An identical invocation is running with an earlier deadline, the caller executes its own.
+    Alone,
+}
+
+#[derive(Debug)]
+struct InFlightInvocation {
+    /// Time after which the executing request gives up, if any.
+    deadline: Option<Instant>,
+    waiters: Vec<oneshot::Sender<SharedInvocationResult>>,
+}
+
+#[derive(Debug)]
+pub struct InFlightInvocations {
+    map: HashMap<InvocationKey, InFlightInvocation>,
+}
+
+impl InFlightInvocations {
+    pub fn new() -> Self {
+        Self {
+            map: HashMap::new(),
+        }
+    }
+
+    /// Waits for an identical invocation already running if it does not give up before the
+    /// caller's deadline. Otherwise, when no identical invocation runs, registers the caller as
+    /// the one executing it.
+    pub fn join(&mut self, key: &InvocationKey, deadline: Option<Instant>) -> JoinedInvocation {
+        match self.map.get_mut(key) {
+            Some(invocation) if lasts_until(invocation.deadline, deadline) => {
+                let (sender, receiver) = oneshot::channel();
+                invocation.waiters.push(sender);
+                info!("Joined in-flight invocation of {:?}, waiters: {:?}", key.name, invocation.waiters.len());
+                JoinedInvocation::Waiting(receiver)
+            }
+            Some(_) => JoinedInvocation::Alone,
+            None => {
+                self.map.insert(key.clone(), InFlightInvocation { deadline, waiters: Vec::new() });
+                JoinedInvocation::Leader
+            }
+        }
+    }
+
+    /// Removes the invocation and returns the requests waiting for its result.
+    pub fn complete(&mut self, key: &InvocationKey) -> Vec<oneshot::Sender<SharedInvocationResult>> {
+        self.map.remove(key).map(|invocation| invocation.waiters).unwrap_or_default()
+    }
+}
+
+/// Whether a request with the first deadline runs at least until the second one.
+fn lasts_until(deadline: Option<Instant>, other: Option<Instant>) -> bool {
+    match (deadline, other) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(deadline), Some(other)) => deadline >= other,
+    }
+}
+
+#[derive(Debug)]
+struct CachedProviders {
+    providers: HashSet<PeerId>,
+    fetched_at: Instant,
+    used_since_fetch: bool,
+    /// A lookup replacing the entry is in progress, it is still served after expiring meanwhile.
+    refreshing: bool,
+}
+
+/// Providers found on the DHT per function, valid for a fixed time to live.
+#[derive(Debug)]
+pub struct ProviderCache {
+    entries: HashMap<String, CachedProviders>,
+    ttl: Duration,
+    hits: u64,
+    misses: u64,
+}
+
+impl ProviderCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            ttl,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn get(&mut self, function_name: &str) -> Option<HashSet<PeerId>> {
+        let ttl = self.ttl;
+        match self.entries.get_mut(function_name) {
+            Some(entry) if entry.fetched_at.elapsed() < ttl || entry.refreshing => {
+                entry.used_since_fetch = true;
+                self.hits += 1;
+                Some(entry.providers.clone())
+            }
+            Some(_) => {
+                self.entries.remove(function_name);
+                self.misses += 1;
+                None
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, function_name: String, providers: HashSet<PeerId>) {
+        if providers.is_empty() {
+            self.entries.remove(&function_name);
+            return;
+        }
+        self.entries.insert(function_name, CachedProviders {
+            providers,
+            fetched_at: Instant::now(),
+            used_since_fetch: false,
+            refreshing: false,
+        });
+    }
+
+    pub fn invalidate(&mut self, function_name: &str) {
+        if self.entries.remove(function_name).is_some() {
+            info!("Invalidated cached providers of {:?}", function_name);
+        }
+    }
+
+    pub fn remove_provider(&mut self, function_name: &str, provider: &PeerId) {
+        if let Some(entry) = self.entries.get_mut(function_name) {
+            entry.providers.remove(provider);
+            if entry.providers.is_empty() {
+                self.entries.remove(function_name);
+            }
+            info!("Removed provider {:?} from cached providers of {:?}", provider, function_name);
+        }
+    }
+
+    /// Returns the functions that were used since they were fetched and are halfway to expiring,
+    /// marking them as refreshing until the new providers are inserted, and drops the expired
+    /// entries nobody used.
+    pub fn due_for_refresh(&mut self) -> Vec<String> {
+        let ttl = self.ttl;
+        self.entries.retain(|_, entry| entry.used_since_fetch || entry.fetched_at.elapsed() < ttl);
+        self.entries
+            .iter_mut()
+            .filter(|(_, entry)| entry.used_since_fetch && !entry.refreshing && entry.fetched_at.elapsed() >= ttl / 2)
+            .map(|(function_name, entry)| {
+                entry.refreshing = true;
+                function_name.clone()
+            })
+            .collect()
+    }
+
+    pub fn stats(&self) -> ProviderCacheStats {
+        ProviderCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> InvocationKey {
+        InvocationKey {
+            name: "echo".to_string(),
+            method: "GET".to_string(),
+            body: None,
+        }
+    }
+
+    #[test]
+    fn invocations_are_joined_by_callers_with_earlier_deadlines() {
+        let mut in_flight = InFlightInvocations::new();
+        let now = Instant::now();
+        assert!(matches!(in_flight.join(&key(), Some(now + Duration::from_secs(10))), JoinedInvocation::Leader));
+        assert!(matches!(in_flight.join(&key(), Some(now + Duration::from_secs(5))), JoinedInvocation::Waiting(_)));
+        assert!(matches!(in_flight.join(&key(), Some(now + Duration::from_secs(10))), JoinedInvocation::Waiting(_)));
+        assert_eq!(in_flight.complete(&key()).len(), 2);
+    }
+
+    #[test]
+    fn invocations_are_not_joined_by_callers_with_later_deadlines() {
+        let mut in_flight = InFlightInvocations::new();
+        let now = Instant::now();
+        assert!(matches!(in_flight.join(&key(), Some(now + Duration::from_secs(5))), JoinedInvocation::Leader));
+        assert!(matches!(in_flight.join(&key(), Some(now + Duration::from_secs(10))), JoinedInvocation::Alone));
+        assert!(matches!(in_flight.join(&key(), None), JoinedInvocation::Alone));
+        assert!(in_flight.complete(&key()).is_empty());
+    }
+
+    #[test]
+    fn draining_providers_are_avoided_unless_no_other_is_left() {
+        let (draining, saturated) = (PeerId::random(), PeerId::random());
+        let mut loads = PeerLoads::new(Duration::from_secs(60));
+        loads.insert(draining, LoadReport { draining: true, ..LoadReport::default() });
+        loads.insert(saturated, LoadReport { cpu: 1.0, ..LoadReport::default() });
+        assert_eq!(loads.unsaturated(&HashSet::from([draining, saturated])), HashSet::from([saturated]));
+        assert_eq!(loads.unsaturated(&HashSet::from([draining])), HashSet::from([draining]));
+    }
+
+    #[test]
+    fn invocations_without_deadline_are_joined_by_every_caller() {
+        let mut in_flight = InFlightInvocations::new();
+        assert!(matches!(in_flight.join(&key(), None), JoinedInvocation::Leader));
+        assert!(matches!(in_flight.join(&key(), None), JoinedInvocation::Waiting(_)));
+        assert!(matches!(in_flight.join(&key(), Some(Instant::now())), JoinedInvocation::Waiting(_)));
+    }
+}
@@ -0,0 +1,169 @@
+use std::fmt;
+use std::io;
+
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use libp2p::request_response::OutboundFailure;
+use serde_json::json;
+
+/// Errors of the node. They are returned by the HTTP API as a status code and a JSON body,
+/// and sent back to the peer requesting a function over P2P.
+#[derive(Debug, Clone)]
+pub(crate) enum FaasError {
+    /// The requested function or resource does not exist.
+    NotFound(String),
+    /// No node in the network provides the function.
+    NoProviders(String),
+    /// The node providing the function failed to execute it.
+    ProviderFailure(String),
+    /// The operation did not finish in time.
+    Timeout(String),
+    /// OpenFaaS failed to build or deploy the function.
+    BackendBuild(String),
+    /// The request is invalid.
+    BadInput(String),
+    /// The caller is not allowed to perform the operation.
+    Forbidden(String),
+    /// The network layer is not available.
+    Network(String),
+    /// The node is not accepting new work, e.g. while draining.
+    Unavailable(String),
+    /// The node is running as many invocations as it can and its wait queue is full.
+    Busy(String),
+    /// The node no longer provides the function, e.g. after it was moved to another node.
+    NotProvided(String),
+    /// Unexpected failure inside the node, e.g. an I/O error.
+    Internal(String),
+}
+
+impl FaasError {
+    /// Short identifier of the error kind, used in the JSON body.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            FaasError::NotFound(_) => "not_found",
+            FaasError::NoProviders(_) => "no_providers",
+            FaasError::ProviderFailure(_) => "provider_failure",
+            FaasError::Timeout(_) => "timeout",
+            FaasError::BackendBuild(_) => "backend_build_failure",
+            FaasError::BadInput(_) => "bad_input",
+            FaasError::Forbidden(_) => "forbidden",
+            FaasError::Network(_) => "network",
+            FaasError::Unavailable(_) => "unavailable",
+            FaasError::Busy(_) => "busy",
+            FaasError::NotProvided(_) => "not_provided",
+            FaasError::Internal(_) => "internal",
+        }
+    }
+
+    pub(crate) fn message(&self) -> &str {
+        match self {
+            FaasError::NotFound(message)
+            | FaasError::NoProviders(message)
+            | FaasError::ProviderFailure(message)
+            | FaasError::Timeout(message)
+            | FaasError::BackendBuild(message)
+            | FaasError::BadInput(message)
+            | FaasError::Forbidden(message)
+            | FaasError::Network(message)
+            | FaasError::Unavailable(message)
+            | FaasError::Busy(message)
+            | FaasError::NotProvided(message)
+            | FaasError::Internal(message) => message,
+        }
+    }
+
+    /// Whether the provider could not be reached or no longer provides the function, so that the
+    /// cached providers are stale. Timeouts chosen by the client and busy providers are not.
+    pub(crate) fn is_stale_provider(&self) -> bool {
+        matches!(
+            self,
+            FaasError::Network(_) | FaasError::ProviderFailure(_) | FaasError::NoProviders(_) | FaasError::NotProvided(_) | FaasError::Unavailable(_)
+        )
+    }
+
+    /// Rebuild an error reported by another peer from its kind and message.
+    pub(crate) fn from_kind(kind: &str, message: String) -> Self {
+        match kind {
+            "not_found" => FaasError::NotFound(message),
+            "no_providers" => FaasError::NoProviders(message),
+            "timeout" => FaasError::Timeout(message),
+            "backend_build_failure" => FaasError::BackendBuild(message),
+            "bad_input" => FaasError::BadInput(message),
+            "forbidden" => FaasError::Forbidden(message),
+            "network" => FaasError::Network(message),
+            "unavailable" => FaasError::Unavailable(message),
+            "busy" => FaasError::Busy(message),
+            "not_provided" => FaasError::NotProvided(message),
+            "internal" => FaasError::Internal(message),
+            _ => FaasError::ProviderFailure(message),
+        }
+    }
+
+    /// JSON body describing the error.
+    pub(crate) fn body(&self) -> serde_json::Value {
+        json!({
+            "error": {
+                "kind": self.kind(),
+                "message": self.message(),
+            }
+        })
+    }
+}
+
+impl fmt::Display for FaasError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.kind(), self.message())
+    }
+}
+
+impl std::error::Error for FaasError {}
+
+impl ResponseError for FaasError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            FaasError::NotFound(_) | FaasError::NoProviders(_) => StatusCode::NOT_FOUND,
+            FaasError::ProviderFailure(_) => StatusCode::BAD_GATEWAY,
+            FaasError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            FaasError::BackendBuild(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            FaasError::BadInput(_) => StatusCode::BAD_REQUEST,
+            FaasError::Forbidden(_) => StatusCode::FORBIDDEN,
+            FaasError::Network(_) | FaasError::Unavailable(_) | FaasError::Busy(_) | FaasError::NotProvided(_) => StatusCode::SERVICE_UNAVAILABLE,
+            FaasError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(self.body())
+    }
+}
+
+impl From<io::Error> for FaasError {
+    fn from(e: io::Error) -> Self {
+        FaasError::Internal(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for FaasError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            FaasError::Timeout(format!("Function did not respond in time: {}", e))
+        } else {
+            FaasError::ProviderFailure(format!("Failed to get response from function: {}", e))
+        }
+    }
+}
+
+impl From<actix_multipart::MultipartError> for FaasError {
+    fn from(e: actix_multipart::MultipartError) -> Self {
+        FaasError::BadInput(format!("Invalid multipart form: {}", e))
+    }
+}
+
+impl From<OutboundFailure> for FaasError {
+    fn from(e: OutboundFailure) -> Self {
+        match e {
+            OutboundFailure::Timeout => FaasError::Timeout("Provider did not respond in time".to_string()),
+            e => FaasError::ProviderFailure(format!("Request to provider failed: {}", e)),
+        }
+    }
+}
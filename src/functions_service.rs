@@ -1,105 +1,747 @@
 use crate::network::NetworkClient;
+use crate::protocol::{FunctionRequest, FunctionResponse};
 use crate::openfaas::OpenFaasClient;
-use crate::data_structures::RequestsInProgress;
-use crate::model::OpenFaaSResponse;
+use crate::data_structures::{Demand, FunctionDemand, RequestsInProgress, InFlightInvocations, InvocationKey, JoinedInvocation, SharedInvocationResult};
+use crate::model::{LoadReport, OpenFaaSResponse, NodeMetrics};
+use crate::placement::{DeployTarget, PlacementSpec};
 use crate::model::detect_and_parse_body;
 use crate::model::serialize_body;
+use crate::error::FaasError;
+use crate::package::{DeployRequest, DeployResponse, FunctionPackage, PackageRequest, PackageResponse, PackageStore, ReplicaReason, ReplicaRequest, ReplicaResponse};
+use crate::policy::InboundPolicy;
+use crate::system;
+use crate::streaming::{self, BodyStream, ResponseWriter};
 
 use tokio::spawn;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify, Semaphore, SemaphorePermit};
+use tokio::time::{interval, sleep, Instant};
 use std::sync::Arc;
 use futures::FutureExt;
+use futures::future::{AbortHandle, Abortable};
+use futures::StreamExt;
+use bytes::Bytes;
+use libp2p::Stream;
+use futures::channel::oneshot;
 
-use std::io;
-use std::collections::HashSet;
-use std::error::Error;
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::{Mutex as StdMutex, PoisonError};
+use std::task::{Context, Poll};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
 use libp2p::PeerId;
+use libp2p::request_response::ResponseChannel;
 
 use actix_multipart::Multipart;
+use actix_web::http::StatusCode;
+use actix_web::ResponseError;
 use serde_json::Value;
 use serde_json::json;
 
-use log::{info, error};
+use log::{info, error, warn};
+
+/// Bounds on the invocations requested by other peers.
+pub(crate) struct InboundLimits {
+    /// Maximum number of invocations executed at the same time.
+    pub(crate) max_concurrent: usize,
+    /// Maximum number of invocations waiting for a free slot, more are answered as busy.
+    pub(crate) max_queued: usize,
+}
+
+/// Thresholds of the automatic replication of hot functions.
+pub(crate) struct AutoscaleConfig {
+    /// Period between two samples of the demand.
+    pub(crate) interval: Duration,
+    /// Invocations per period from which a function gets a new replica.
+    pub(crate) scale_up_invocations: u64,
+    /// Invocations in progress from which a function gets a new replica.
+    pub(crate) scale_up_in_progress: usize,
+    /// Time without invocations after which a replica is removed.
+    pub(crate) scale_down_idle: Duration,
+}
+
+impl AutoscaleConfig {
+    /// Time to wait before asking for another replica of the same function, leaving time
+    /// to deploy the previous one and for its load report to list it.
+    fn scale_up_cooldown(&self) -> Duration {
+        self.interval * 6
+    }
+}
+
+/// Result of a deployment on the target nodes: the nodes providing the function and the
+/// nodes on which the deployment failed.
+pub(crate) struct Deployment {
+    pub(crate) function: String,
+    pub(crate) providers: Vec<PeerId>,
+    pub(crate) failures: Vec<(PeerId, FaasError)>,
+}
 
 pub(crate) struct FunctionsService {
     pub(crate) nc: Arc<NetworkClient>,
     pub(crate) ofc: Arc<OpenFaasClient>,
     pub(crate) rp: Arc<Mutex<RequestsInProgress>>,
     pub(crate) peer_id: libp2p::PeerId,
+    pub(crate) cacheable_functions: HashSet<String>,
+    // Std mutex so that a dropped request can unregister itself synchronously
+    pub(crate) in_flight: Arc<StdMutex<InFlightInvocations>>,
+    pub(crate) inbound_policy: InboundPolicy,
+    pub(crate) package_store: PackageStore,
+    draining: AtomicBool,
+    drain_requested: Notify,
+    inbound_in_progress: AtomicUsize,
+    inbound_slots: Semaphore,
+    inbound_queued: AtomicUsize,
+    max_inbound_queued: usize,
+    // Invocations from other peers in progress, to abort them when the caller cancels
+    inbound_executions: StdMutex<HashMap<(PeerId, String), AbortHandle>>,
+    demand: StdMutex<FunctionDemand>,
+    autoscaling: Option<AutoscaleConfig>,
+    // Replicas deployed on request of other peers, with the time they were deployed
+    replicas: StdMutex<HashMap<String, std::time::Instant>>,
+    // Last time another peer was asked to replicate each function
+    scale_ups: StdMutex<HashMap<String, Instant>>,
+    labels: HashMap<String, String>,
+    accept_remote_deploy: bool,
 }
 
 impl FunctionsService {
     pub(crate) fn new(
         nc: Arc<NetworkClient>,
         ofc: Arc<OpenFaasClient>,
-        rp: Arc<Mutex<RequestsInProgress>>,
         peer_id: PeerId,
+        cacheable_functions: HashSet<String>,
+        inbound_policy: InboundPolicy,
+        inbound_limits: InboundLimits,
+        package_store: PackageStore,
     ) -> Self {
-        Self { nc, ofc, rp, peer_id }
+        Self {
+            nc,
+            ofc,
+            rp: Arc::new(Mutex::new(RequestsInProgress::new())),
+            peer_id,
+            cacheable_functions,
+            in_flight: Arc::new(StdMutex::new(InFlightInvocations::new())),
+            inbound_policy,
+            package_store,
+            draining: AtomicBool::new(false),
+            drain_requested: Notify::new(),
+            inbound_in_progress: AtomicUsize::new(0),
+            inbound_slots: Semaphore::new(inbound_limits.max_concurrent),
+            inbound_queued: AtomicUsize::new(0),
+            max_inbound_queued: inbound_limits.max_queued,
+            inbound_executions: StdMutex::new(HashMap::new()),
+            demand: StdMutex::new(FunctionDemand::new()),
+            autoscaling: None,
+            replicas: StdMutex::new(HashMap::new()),
+            scale_ups: StdMutex::new(HashMap::new()),
+            labels: HashMap::new(),
+            accept_remote_deploy: false,
+        }
+    }
+
+    /// Labels of the node, published in its load reports to target deployments.
+    pub(crate) fn with_labels(mut self, labels: HashMap<String, String>) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Deploy the functions other peers send to this node.
+    pub(crate) fn with_remote_deploy(mut self) -> Self {
+        self.accept_remote_deploy = true;
+        self
+    }
+
+    /// Replicate the hot functions provided by this node on other peers, and accept to deploy
+    /// replicas requested by other peers.
+    pub(crate) fn with_autoscaling(mut self, config: AutoscaleConfig) -> Self {
+        self.autoscaling = Some(config);
+        self
+    }
+
+    /// Execute a function requested by another peer on the local OpenFaaS and respond with the result,
+    /// if the inbound policy allows the peer to invoke it and the node is not busy.
+    pub(crate) async fn handle_inbound_request(&self, peer: PeerId, request: FunctionRequest, channel: ResponseChannel<FunctionResponse>) {
+        self.inbound_in_progress.fetch_add(1, Ordering::SeqCst);
+        info!("Inbound request {:?} from peer {:?}, request id: {:?}", request.function, peer, request.request_id);
+        // The request is the function name followed by the path and query.
+        let function_name = request.function.split(['/', '?']).next().unwrap_or_default();
+        let execution = async {
+            match self.admit_inbound(&peer, function_name).await {
+                // Http request to localhost:8000/functions/name
+                Ok(_permit) => {
+                    let _demand = self.track_demand(function_name);
+                    self.ofc.request_function(&request.function, &request.method, request.body.clone(), request.timeout()).await
+                }
+                Err(e) => {
+                    warn!("Rejected inbound request {:?} from peer {:?}: {:?}", request.function, peer, e);
+                    Err(e)
+                }
+            }
+        };
+
+        // Register the execution so that the caller can cancel it.
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        let execution_key = request.request_id.clone().map(|request_id| (peer, request_id));
+        if let Some(key) = &execution_key {
+            self.inbound_executions.lock().unwrap_or_else(PoisonError::into_inner).insert(key.clone(), abort_handle);
+        }
+        let resp = Abortable::new(execution, abort_registration).await;
+        if let Some(key) = &execution_key {
+            self.inbound_executions.lock().unwrap_or_else(PoisonError::into_inner).remove(key);
+        }
+        let Ok(resp) = resp else {
+            info!("Inbound request {:?} from peer {:?} cancelled", request.function, peer);
+            self.inbound_in_progress.fetch_sub(1, Ordering::SeqCst);
+            return;
+        };
+        info!("Response received for inbound request");
+        let response = match resp {
+            Ok(resp) => FunctionResponse::new(resp.status, resp.body),
+            Err(err) => {
+                error!("Failed to send request: {:?}", err);
+                FunctionResponse {
+                    error_kind: Some(err.kind().to_string()),
+                    ..FunctionResponse::new(err.status_code().as_u16(), err.body().to_string().into_bytes())
+                }
+            }
+        };
+        if let Err(err) = self.nc.respond_function(response, channel).await {
+            error!("Failed to respond with request result: {:?}", err);
+        }
+        self.inbound_in_progress.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Execute a function requested by another peer over a stream, streaming the bodies
+    /// between the peer and the function, if the inbound policy allows it and the node is not busy.
+    pub(crate) async fn handle_inbound_stream(&self, peer: PeerId, stream: Stream) {
+        self.inbound_in_progress.fetch_add(1, Ordering::SeqCst);
+        let result = match streaming::receive_request(stream).await {
+            Ok((request, body, writer)) => {
+                info!("Inbound stream {:?} from peer {:?}, request id: {:?}", request.function, peer, request.request_id);
+                // The request is the function name followed by the path and query.
+                let function_name = request.function.split(['/', '?']).next().unwrap_or_default();
+                match self.admit_inbound(&peer, function_name).await {
+                    Ok(_permit) => {
+                        let _demand = self.track_demand(function_name);
+                        match self.ofc.request_function_stream(&request.function, &request.method, body, request.timeout()).await {
+                            Ok((status, response_body)) => writer.respond(FunctionResponse::new(status, Vec::new()), response_body).await,
+                            Err(e) => respond_stream_error(writer, e).await,
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Rejected inbound stream {:?} from peer {:?}: {:?}", request.function, peer, e);
+                        respond_stream_error(writer, e).await
+                    }
+                }
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            error!("Inbound stream from peer {:?} failed: {:?}", peer, e);
+        }
+        self.inbound_in_progress.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Execute the function streaming the request and response bodies. The request body is
+    /// sent to a single provider, so it is not raced between providers.
+    pub(crate) async fn execute_function_stream(&self, name: String, method: &str, path_and_query: Option<String>, body: BodyStream, timeout: Option<Duration>) -> Result<(u16, BodyStream), FaasError> {
+        self.check_accepting()?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let execution = self.execute_function_stream_until(name, method, path_and_query, body, deadline);
+        match (timeout, deadline) {
+            (Some(timeout), Some(deadline)) => {
+                let (status, body) = tokio::time::timeout(timeout, execution)
+                    .await
+                    .unwrap_or_else(|_| Err(FaasError::Timeout(format!("Function did not respond within {:?}", timeout))))?;
+                // The deadline also bounds the response body, which is streamed afterwards.
+                Ok((status, streaming::with_deadline(body, deadline)))
+            }
+            _ => execution.await,
+        }
+    }
+
+    async fn execute_function_stream_until(&self, name: String, method: &str, path_and_query: Option<String>, body: BodyStream, deadline: Option<Instant>) -> Result<(u16, BodyStream), FaasError> {
+        let providers = self.find_providers(&name).await;
+        info!("providers: {:?}", providers);
+        if providers.is_empty() {
+            return Err(FaasError::NoProviders(format!("No providers found for function {}", name)));
+        }
+        // Avoid the providers other nodes already saturated.
+        let providers = self.nc.peer_loads().await.unsaturated(&providers);
+        let function = name.clone() + path_and_query.as_deref().unwrap_or_default();
+        let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+
+        if providers.contains(&self.peer_id) {
+            let _demand = self.track_demand(&name);
+            return self.ofc.request_function_stream(&function, method, body, timeout).await;
+        }
+
+        let latencies = self.nc.peer_latencies().await;
+        let provider = {
+            let mut rp_instance = self.rp.lock().await;
+            let provider = rp_instance.find_not_in_progress_peer(&providers, &latencies)
+                .or_else(|| rp_instance.get_peer(&providers, &latencies))
+                .or_else(|| latencies.nearest(&providers))
+                .ok_or_else(|| FaasError::NoProviders(format!("No providers found for function {}", name)))?;
+            rp_instance.push_req(&provider, false);
+            provider
+        };
+        let guard = RequestInProgressGuard { rp: Arc::clone(&self.rp), provider };
+        match self.nc.request_function_stream(provider, function, method.to_string(), body, timeout).await {
+            Ok((response, response_body)) => {
+                // The body was sent to this provider and cannot be sent to another one, make the
+                // next invocations avoid it.
+                if let Some(FaasError::NotProvided(_) | FaasError::Unavailable(_)) = fail_over_error(&provider, &response) {
+                    self.nc.invalidate_provider(&name, &provider).await;
+                }
+                // The request is in progress until the whole response is received.
+                let response_body = InProgressBody { body: response_body, _guard: guard }.boxed();
+                Ok((response.status, response_body))
+            }
+            Err(e) => {
+                if e.is_stale_provider() {
+                    self.nc.invalidate_provider(&name, &provider).await;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Abort an invocation from another peer, because the peer stopped waiting for it.
+    pub(crate) fn cancel_inbound_request(&self, peer: PeerId, request_id: String) {
+        let abort_handle = self.inbound_executions.lock().unwrap_or_else(PoisonError::into_inner).remove(&(peer, request_id));
+        if let Some(abort_handle) = abort_handle {
+            abort_handle.abort();
+        }
+    }
+
+    /// Admit an invocation from another peer: this node must not be draining, the inbound policy
+    /// must allow it, and this node must still provide the function and have a free execution slot.
+    async fn admit_inbound(&self, peer: &PeerId, function_name: &str) -> Result<SemaphorePermit<'_>, FaasError> {
+        self.check_accepting()?;
+        self.inbound_policy.check(peer, function_name)?;
+        if !self.provides(function_name).await {
+            return Err(FaasError::NotProvided(format!("Function {} is no longer provided by this node", function_name)));
+        }
+        self.acquire_inbound_slot().await
+    }
+
+    /// Whether this node provides the function. Its provider records stay on other DHT nodes until
+    /// they expire, so peers may still send invocations of functions moved or removed from it.
+    async fn provides(&self, function_name: &str) -> bool {
+        self.nc.provided_functions().await.unwrap_or_default().iter().any(|provided| provided == function_name)
+    }
+
+    /// Providers of the function, without this node when the DHT still returns its stale records.
+    async fn find_providers(&self, function_name: &str) -> HashSet<PeerId> {
+        let mut providers = self.nc.get_providers(function_name.to_string()).await;
+        if providers.contains(&self.peer_id) && !self.provides(function_name).await {
+            providers.remove(&self.peer_id);
+        }
+        providers
+    }
+
+    /// Wait for a free execution slot, unless the wait queue is full.
+    async fn acquire_inbound_slot(&self) -> Result<SemaphorePermit<'_>, FaasError> {
+        if let Ok(permit) = self.inbound_slots.try_acquire() {
+            return Ok(permit);
+        }
+        if self.inbound_queued.fetch_add(1, Ordering::SeqCst) >= self.max_inbound_queued {
+            self.inbound_queued.fetch_sub(1, Ordering::SeqCst);
+            return Err(FaasError::Busy("Node is running as many invocations as it can, try another provider".to_string()));
+        }
+        // The wait is aborted when the caller cancels the invocation, leave the queue then too.
+        let _queued = InboundQueueGuard { queued: &self.inbound_queued };
+        self.inbound_slots.acquire().await.map_err(|_| FaasError::Unavailable("Node is not accepting invocations".to_string()))
+    }
+
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    /// Stop accepting new invocations and ask the node to drain and shut down.
+    pub(crate) fn request_drain(&self) {
+        if !self.draining.swap(true, Ordering::SeqCst) {
+            info!("Drain requested");
+            self.drain_requested.notify_one();
+        }
+    }
+
+    /// Wait until a drain is requested through the API.
+    pub(crate) async fn drain_requested(&self) {
+        self.drain_requested.notified().await;
+    }
+
+    /// Stop accepting invocations, withdraw the provider records of the local functions
+    /// and wait for the requests in progress to finish, up to the given timeout.
+    ///
+    /// Withdrawing the records only removes them from the local store, the other DHT nodes keep
+    /// theirs until they expire. The drain is announced in a load report right away so that peers
+    /// stop choosing this node meanwhile.
+    pub(crate) async fn drain(&self, timeout: Duration) {
+        self.draining.store(true, Ordering::SeqCst);
+        match self.nc.stop_providing_all().await {
+            Ok(function_names) => info!("Withdrew provider records of {:?}", function_names),
+            Err(e) => error!("Failed to withdraw provider records: {:?}", e),
+        }
+        if let Err(e) = self.nc.publish_load_report(self.load_report().await).await {
+            error!("Failed to announce the drain: {:?}", e);
+        }
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let requests_in_progress = !self.rp.lock().await.is_empty();
+            let inbound_in_progress = self.inbound_in_progress.load(Ordering::SeqCst);
+            if !requests_in_progress && inbound_in_progress == 0 {
+                info!("All requests in progress finished");
+                return;
+            }
+            if Instant::now() >= deadline {
+                warn!("Drain timeout reached with requests in progress: {:?}, inbound requests in progress: {:?}", requests_in_progress, inbound_in_progress);
+                return;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Count an execution of the function on this node until the returned guard is dropped.
+    fn track_demand(&self, function_name: &str) -> DemandGuard<'_> {
+        self.demand.lock().unwrap_or_else(PoisonError::into_inner).start(function_name);
+        DemandGuard { demand: &self.demand, function_name: function_name.to_string() }
+    }
+
+    /// Sample the demand for the functions periodically, asking a less loaded peer to replicate
+    /// the hot functions provided by this node and removing the replicas no longer invoked.
+    pub(crate) async fn run_autoscaler(self: Arc<Self>) {
+        let Some(config) = &self.autoscaling else {
+            return;
+        };
+        let mut autoscale_interval = interval(config.interval);
+        loop {
+            autoscale_interval.tick().await;
+            if self.is_draining() {
+                continue;
+            }
+            let demand = self.demand.lock().unwrap_or_else(PoisonError::into_inner).sample();
+            let provided = self.nc.provided_functions().await.unwrap_or_default();
+            for function_name in provided {
+                let Some(function_demand) = demand.get(&function_name) else {
+                    continue;
+                };
+                if function_demand.invocations >= config.scale_up_invocations || function_demand.in_progress >= config.scale_up_in_progress {
+                    self.scale_up(&function_name, function_demand, config).await;
+                }
+            }
+            self.scale_down_idle_replicas(&demand, config).await;
+        }
+    }
+
+    /// Ask the least loaded peer not deploying the function yet to deploy a replica of it.
+    async fn scale_up(&self, function_name: &str, demand: &Demand, config: &AutoscaleConfig) {
+        let recently_scaled = self.scale_ups.lock().unwrap_or_else(PoisonError::into_inner)
+            .get(function_name)
+            .is_some_and(|scaled_at| scaled_at.elapsed() < config.scale_up_cooldown());
+        if recently_scaled {
+            return;
+        }
+        let Some(peer) = self.nc.peer_loads().await.least_loaded_without(function_name).first().copied() else {
+            info!("Function {} is hot but no peer can take a replica", function_name);
+            return;
+        };
+        info!("Function {} is hot, {} invocations and {} in progress, asking peer {:?} for a replica",
+            function_name, demand.invocations, demand.in_progress, peer);
+        let digest = self.package_store.digest(function_name);
+        match self.nc.request_replica(peer, function_name.to_string(), ReplicaReason::Demand, digest).await {
+            Ok(ReplicaResponse { accepted: true }) => {
+                self.scale_ups.lock().unwrap_or_else(PoisonError::into_inner).insert(function_name.to_string(), Instant::now());
+            }
+            Ok(ReplicaResponse { accepted: false }) => info!("Peer {:?} declined to replicate function {}", peer, function_name),
+            Err(e) => warn!("Failed to ask peer {:?} to replicate function {}: {:?}", peer, function_name, e),
+        }
+    }
+
+    /// Remove the replicas deployed for other peers that were not invoked during the idle time.
+    async fn scale_down_idle_replicas(&self, demand: &HashMap<String, Demand>, config: &AutoscaleConfig) {
+        let idle: Vec<String> = self.replicas.lock().unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|(function_name, deployed_at)| match demand.get(*function_name) {
+                Some(demand) => demand.in_progress == 0 && demand.last_invoked.max(**deployed_at).elapsed() >= config.scale_down_idle,
+                None => deployed_at.elapsed() >= config.scale_down_idle,
+            })
+            .map(|(function_name, _)| function_name.clone())
+            .collect();
+        for function_name in idle {
+            // Keep the replicas the function needs to meet its replication factor.
+            let min_replicas = self.package_store.min_replicas(&function_name);
+            if min_replicas > 1 && self.live_providers(&function_name).await.len() <= min_replicas {
+                continue;
+            }
+            info!("Replica of function {} is idle, removing it", function_name);
+            // Invocations still routed here by stale provider records are answered as not provided
+            // and fail over to the other providers.
+            if let Err(e) = self.nc.stop_providing(function_name.clone()).await {
+                error!("Failed to stop providing function {}: {:?}", function_name, e);
+                continue;
+            }
+            if let Err(e) = self.retire_function(&function_name, config.interval).await {
+                error!("Failed to remove replica of function {}: {:?}", function_name, e);
+            }
+        }
+    }
+
+    /// Deploy a replica of a function requested by another peer, when autoscaling is enabled,
+    /// the node has room for it and the inbound policy allows the peer to invoke the function.
+    pub(crate) async fn handle_replica_request(&self, peer: PeerId, request: ReplicaRequest, channel: ResponseChannel<ReplicaResponse>) {
+        let function_name = request.function;
+        // Replicas restoring the replication factor are accepted even without autoscaling.
+        let accepted = (self.autoscaling.is_some() || request.reason == ReplicaReason::ReplicationFactor)
+            && !self.is_draining()
+            && self.inbound_policy.check(&peer, &function_name).is_ok()
+            && !self.load_report().await.is_saturated()
+            && !self.nc.provided_functions().await.unwrap_or_default().contains(&function_name);
+        info!("Peer {:?} asked for a replica of function {:?}, accepted: {}", peer, function_name, accepted);
+        if let Err(e) = self.nc.respond_replica(ReplicaResponse { accepted }, channel).await {
+            error!("Failed to answer replica request: {:?}", e);
+        }
+        if !accepted {
+            return;
+        }
+        match self.replicate_function(&function_name, request.digest).await {
+            Ok(digest) => {
+                info!("Deployed replica {} of function {}", digest, function_name);
+                if request.reason == ReplicaReason::Demand {
+                    self.replicas.lock().unwrap_or_else(PoisonError::into_inner).insert(function_name, std::time::Instant::now());
+                }
+            }
+            Err(e) => error!("Failed to deploy replica of function {}: {:?}", function_name, e),
+        }
+    }
+
+    /// Providers of the function still alive: this node and the peers whose load reports did not expire.
+    async fn live_providers(&self, function_name: &str) -> HashSet<PeerId> {
+        let providers = self.nc.get_providers(function_name.to_string()).await;
+        let loads = self.nc.peer_loads().await;
+        let (live, dead): (HashSet<PeerId>, HashSet<PeerId>) = providers
+            .into_iter()
+            .partition(|provider| *provider == self.peer_id || loads.get(provider).is_some());
+        // Provider records of dead nodes stay in the DHT until they expire, stop using them.
+        for provider in &dead {
+            self.nc.invalidate_provider(function_name, provider).await;
+        }
+        live
+    }
+
+    /// Check periodically that the functions provided by this node with a replication factor have
+    /// enough live providers, and ask healthy peers for replicas of the ones missing providers.
+    pub(crate) async fn run_replication_check(self: Arc<Self>, period: Duration) {
+        let mut check_interval = interval(period);
+        loop {
+            check_interval.tick().await;
+            if self.is_draining() {
+                continue;
+            }
+            let provided = self.nc.provided_functions().await.unwrap_or_default();
+            for (function_name, min_replicas) in self.package_store.replicated_functions() {
+                if provided.contains(&function_name) {
+                    self.maintain_replicas(&function_name, min_replicas).await;
+                }
+            }
+        }
+    }
+
+    async fn maintain_replicas(&self, function_name: &str, min_replicas: usize) {
+        let providers = self.live_providers(function_name).await;
+        if providers.len() >= min_replicas {
+            return;
+        }
+        // Only the live provider with the lowest peer ID starts the replicas, so that the
+        // providers do not start one each.
+        if providers.iter().min() != Some(&self.peer_id) {
+            return;
+        }
+        let missing = min_replicas - providers.len();
+        warn!("Function {} has {} live providers out of {}, starting {} replicas", function_name, providers.len(), min_replicas, missing);
+        let candidates = self.nc.peer_loads().await.least_loaded_without(function_name);
+        let digest = self.package_store.digest(function_name);
+        let mut started = 0;
+        for peer in candidates.into_iter().filter(|peer| !providers.contains(peer)) {
+            if started == missing {
+                break;
+            }
+            match self.nc.request_replica(peer, function_name.to_string(), ReplicaReason::ReplicationFactor, digest.clone()).await {
+                Ok(ReplicaResponse { accepted: true }) => started += 1,
+                Ok(ReplicaResponse { accepted: false }) => info!("Peer {:?} declined to replicate function {}", peer, function_name),
+                Err(e) => warn!("Failed to ask peer {:?} to replicate function {}: {:?}", peer, function_name, e),
+            }
+        }
+        if started < missing {
+            warn!("Only {} of the {} missing replicas of function {} could be started", started, missing, function_name);
+        }
+    }
+
+    /// Publish the load of this node to the other peers periodically.
+    pub(crate) async fn run_load_reports(self: Arc<Self>, period: Duration) {
+        let mut report_interval = interval(period);
+        loop {
+            report_interval.tick().await;
+            let report = self.load_report().await;
+            if let Err(e) = self.nc.publish_load_report(report).await {
+                error!("Failed to publish load report: {:?}", e);
+            }
+        }
+    }
+
+    async fn load_report(&self) -> LoadReport {
+        LoadReport {
+            cpu: system::cpu_load().unwrap_or_default(),
+            memory: system::memory_usage().unwrap_or_default(),
+            in_flight: self.inbound_in_progress.load(Ordering::SeqCst)
+                .saturating_sub(self.inbound_queued.load(Ordering::SeqCst)) as u64,
+            queue_len: self.inbound_queued.load(Ordering::SeqCst) as u64,
+            warm_functions: self.nc.provided_functions().await.unwrap_or_default(),
+            labels: self.labels.clone(),
+            free_slots: self.inbound_slots.available_permits() as u64,
+            free_memory_mb: system::available_memory_mb().unwrap_or_default(),
+            draining: self.is_draining(),
+        }
+    }
+
+    fn check_accepting(&self) -> Result<(), FaasError> {
+        if self.is_draining() {
+            return Err(FaasError::Unavailable("Node is draining and does not accept new invocations".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Execute the function, giving up once the timeout, if any, elapses.
+    /// The providers are told the time left so that they give up too.
+    pub(crate) async fn execute_function(&self, name: String, method: &str, body: Option<Vec<u8>>, path_and_query_field: &Option<String>, timeout: Option<Duration>) -> Result<OpenFaaSResponse, FaasError>{
+        self.check_accepting()?;
+        let Some(timeout) = timeout else {
+            return self.execute_function_until(name, method, body, path_and_query_field, None).await;
+        };
+        let function_name = name.clone();
+        // Dropping the execution cancels the requests to the providers.
+        tokio::time::timeout(timeout, self.execute_function_until(name, method, body, path_and_query_field, Some(Instant::now() + timeout)))
+            .await
+            .unwrap_or_else(|_| Err(FaasError::Timeout(format!("Function {} did not finish within {:?}", function_name, timeout))))
+    }
+
+    async fn execute_function_until(&self, name: String, method: &str, body: Option<Vec<u8>>, path_and_query_field: &Option<String>, deadline: Option<Instant>) -> Result<OpenFaaSResponse, FaasError>{
+        if !self.cacheable_functions.contains(&name) {
+            return self.execute_function_uncoalesced(name, method, body, path_and_query_field, deadline).await;
+        }
+
+        let key = InvocationKey {
+            name: name.clone() + path_and_query_field.as_deref().unwrap_or_default(),
+            method: method.to_string(),
+            body: body.clone(),
+        };
+        // Wait for an identical invocation already in progress, or become the one executing it.
+        // Invocations giving up before the caller's deadline are not joined, their timeout would
+        // be the caller's.
+        loop {
+            let joined = self.in_flight.lock().unwrap_or_else(PoisonError::into_inner).join(&key, deadline.map(Instant::into_std));
+            match joined {
+                JoinedInvocation::Waiting(receiver) => match receiver.await {
+                    Ok(result) => {
+                        info!("Coalesced invocation of {:?} finished", key.name);
+                        return result;
+                    }
+                    // The executing request was dropped before finishing, try again.
+                    Err(_) => continue,
+                },
+                JoinedInvocation::Leader => break,
+                JoinedInvocation::Alone => {
+                    return self.execute_function_uncoalesced(name, method, body, path_and_query_field, deadline).await;
+                }
+            }
+        }
+
+        let mut guard = InFlightGuard { in_flight: &self.in_flight, key, done: false };
+        let result = self.execute_function_uncoalesced(name, method, body, path_and_query_field, deadline).await;
+        for waiter in guard.complete() {
+            let _ = waiter.send(result.clone());
+        }
+        result
     }
 
-    pub(crate) async fn execute_function(&self, mut name: String, method: &String, body: Option<Vec<u8>>, path_and_query_field: &Option<String>) -> Result<OpenFaaSResponse, Box<dyn Error>>{
+    async fn execute_function_uncoalesced(&self, mut name: String, method: &str, body: Option<Vec<u8>>, path_and_query_field: &Option<String>, deadline: Option<Instant>) -> Result<OpenFaaSResponse, FaasError>{
         let network_client = &self.nc;
-        let openfaas_client = &self.ofc;
-        let requests_in_progress = &self.rp;
         let peer_id = &self.peer_id;
 
-        let function_response_result;
+        let function_name = name.clone();
+
         // Locate all nodes providing the function.
-        let providers = network_client.get_providers(name.clone()).await;
+        let providers = self.find_providers(&name).await;
         info!("providers: {:?}", providers);
         if providers.is_empty() {
-            return Err(Box::new(io::Error::new(io::ErrorKind::NotFound, "No providers found for function")));
+            return Err(FaasError::NoProviders(format!("No providers found for function {}", name)));
         }
+        // Avoid the providers other nodes already saturated.
+        let providers = network_client.peer_loads().await.unsaturated(&providers);
         
-        if path_and_query_field.is_some() {
-            name = name + path_and_query_field.as_ref().unwrap();
+        if let Some(path_and_query) = path_and_query_field {
+            name = name + path_and_query;
         }
 
         // Request the content of the file from each node.
         info!("providers: {:?}", providers);
         // Check if providers length is 1 and then check if it is the same as the peer_id
         // If it is, then return the file content
-        match self.function_request(providers, peer_id, &name, &method, &body, requests_in_progress, openfaas_client, network_client).await
+        let function_response_result = match self.function_request(providers, peer_id, &name, method, &body, deadline).await
         {
-            Ok(resp) => function_response_result = resp,
-            Err(e) => return Err(e)
-        }
+            Ok(resp) => resp,
+            Err(e) => {
+                // Cached providers may be stale, look them up again on the next request
+                if e.is_stale_provider() {
+                    network_client.invalidate_providers(&function_name).await;
+                }
+                return Err(e)
+            }
+        };
         
-        return Ok(function_response_result)
+        Ok(function_response_result)
     }
 
-    pub(crate) async fn execute_function_manycall(&self, name: &String, items: Vec<Value>) -> Result<Vec<Value>, Box<dyn Error>> {
+    /// Execute the function once per item, spreading the invocations among the providers. The
+    /// invocations still running when the timeout, if any, elapses fail with a timeout error.
+    pub(crate) async fn execute_function_manycall(&self, name: &str, items: Vec<Value>, timeout: Option<Duration>) -> Result<Vec<Value>, FaasError> {
+        self.check_accepting()?;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
         let network_client = &self.nc;
         let openfaas_client = &self.ofc;
         let requests_in_progress = &self.rp;
         let peer_id = &self.peer_id;
 
-        let providers = network_client.get_providers(name.clone()).await;
+        let providers = self.find_providers(name).await;
         info!("providers: {:?}", providers);
 
         if providers.is_empty() {
-            return Err(Box::new(io::Error::new(io::ErrorKind::NotFound,"Could not find provider")));
+            return Err(FaasError::NoProviders(format!("No providers found for function {}", name)));
         }
-            
+        // Avoid the providers other nodes already saturated.
+        let providers = network_client.peer_loads().await.unsaturated(&providers);
+
         let items_result: Arc<Mutex<Vec<serde_json::Value>>> = Arc::new(Mutex::new(vec![serde_json::Value::String("".to_string()); items.len()]));
 
         let providers_not_used = Arc::new(Mutex::new(providers.clone()));
-        let mut actual_item = 0;
+        let latencies = Arc::new(network_client.peer_latencies().await);
         let mut handles = vec![];
         let items_clone = items.clone();
         
-        for item in items_clone {
+        for (actual_item, item) in items_clone.into_iter().enumerate() {
             // TODO remove clone here as used in other scope
             let network_client_clone = network_client.clone();
             let openfaas_client_clone = Arc::clone(openfaas_client);
-            let peer_id_clone = peer_id.clone();
+            let peer_id_clone = *peer_id;
             let requests_in_progress_clone = Arc::clone(requests_in_progress);
             let providers_clone: HashSet<PeerId> = providers.clone();
-            let name_clone = name.clone();
+            let name_clone = name.to_string();
             let method = "POST".to_string();
             let shared_result         = Arc::clone(&items_result);
             let shared_providers_not_used    = Arc::clone(&providers_not_used);
+            let latencies = Arc::clone(&latencies);
 
             // Spawn a new asynchronous task
             let handle_call = spawn(async move {
@@ -113,7 +755,7 @@ impl FunctionsService {
                         if pnu_lock.is_empty() {
                             provider_nip = None;
                         } else {
-                            provider_nip = rp_instance.find_not_in_progress_peer(&providers_clone);
+                            provider_nip = rp_instance.find_not_in_progress_peer(&providers_clone, &latencies);
                         }
                     }
 
@@ -129,9 +771,16 @@ impl FunctionsService {
                             provider = p;
                         }
                         None => {
-                            // Get one from struct
+                            // Get one from struct, any provider will do if none is queued
                             let mut pnu_lock = shared_providers_not_used.lock().await;
-                            provider = rp_instance.get_peer(&providers_clone).unwrap();
+                            let queued_provider = rp_instance.get_peer(&providers_clone, &latencies)
+                                .or_else(|| latencies.nearest(&providers_clone));
+                            let Some(queued_provider) = queued_provider else {
+                                let mut results = shared_result.lock().await;
+                                results[actual_item] = FaasError::NoProviders(format!("No providers found for function {}", name_clone)).body();
+                                return;
+                            };
+                            provider = queued_provider;
                             if pnu_lock.contains(&provider) {
                                 first_use = true;
                                 pnu_lock.remove(&provider);
@@ -141,41 +790,47 @@ impl FunctionsService {
                     }
                 }
                 let body = item.to_string().into_bytes();
+                let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
                 let function_response_status;
                 let function_response_result;
                 if provider == peer_id_clone {
-                    let resp;
-
-                    resp = openfaas_client_clone.request_function(&name_clone, &method, Some(body)).await;
+                    let resp = openfaas_client_clone.request_function(&name_clone, &method, Some(body), timeout).await;
 
                     match resp {
                         Ok(resp) => {
-                            function_response_status = resp.status().as_u16();
-                            let resp_body = resp.bytes().await.unwrap().to_vec();
-                            function_response_result = resp_body;
+                            function_response_status = resp.status;
+                            function_response_result = resp.body;
                         }
                         Err(err) => {
                             error!("Failed to get response from function {}: {:?}", name_clone, err);
-                            function_response_status = 500;
-                            function_response_result = "Failed to get response from function".to_string().into_bytes();
+                            function_response_status = err.status_code().as_u16();
+                            function_response_result = err.body().to_string().into_bytes();
                         }
                     }
                 }
                 else {
                     let body = body.clone();
-                    let function_response;
-
-                    function_response = network_client_clone.request_function(provider, name_clone, method, Some(body)).await;
+                    let request = network_client_clone.request_function(provider, name_clone.clone(), method, Some(body), timeout);
+                    // Dropping the request past the deadline asks the provider to cancel it.
+                    let function_response = match timeout {
+                        Some(timeout) => tokio::time::timeout(timeout, request)
+                            .await
+                            .unwrap_or_else(|_| Err(FaasError::Timeout(format!("Function {} did not finish within {:?}", name_clone, timeout)))),
+                        None => request.await,
+                    };
                     
                     match function_response {
                         Ok(function_response) => {
-                            function_response_status = function_response.0;
-                            function_response_result = function_response.1
+                            function_response_status = function_response.status;
+                            function_response_result = function_response.body
                         },
                         Err(e) => {
                             error!("Response from provider failed: {:?}", e);
-                            function_response_status = 500;
-                            function_response_result = "Response from provider failed".to_string().into_bytes()
+                            if e.is_stale_provider() {
+                                network_client_clone.invalidate_provider(&name_clone, &provider).await;
+                            }
+                            function_response_status = e.status_code().as_u16();
+                            function_response_result = e.body().to_string().into_bytes()
                         }
                     };
                 }
@@ -200,11 +855,12 @@ impl FunctionsService {
             });
             // Collect task handles to await them later
             handles.push(handle_call);
-            actual_item += 1;
         }
 
         for handle in handles {
-            handle.await.unwrap();
+            if let Err(e) = handle.await {
+                error!("Manycall invocation task failed: {:?}", e);
+            }
         }
 
         // Decrease in progress requests from providers in this manycall
@@ -220,46 +876,319 @@ impl FunctionsService {
         Ok(response)
     }
 
-    pub(crate) async fn deploy_function(&self, payload: Multipart) -> Result<String, Box<dyn Error>> {
-        let network_client = &self.nc;
-        // Deploy the function to openfaas
-        let deployment_result = self.deploy_openfaas(payload, None).await;
-        let function_name;
-        match deployment_result {
-            Ok(f_n) => function_name = f_n,
-            Err(e) => {
-                error!("Failed to deploy function: {:?}", e);
-                return Err(Box::new(io::Error::new(io::ErrorKind::Other, "Failed to deploy function")));
+    /// Deploy a new function on the target nodes, with the name generated by the first node.
+    pub(crate) async fn deploy_function(&self, payload: Multipart, min_replicas: usize, target: &DeployTarget) -> Result<Deployment, FaasError> {
+        self.check_accepting()?;
+        let package = FunctionPackage::from_multipart(payload).await?;
+        self.deploy_to(package, None, min_replicas, target).await
+    }
+
+    /// Deploy or update the function on the target nodes.
+    pub(crate) async fn deploy_known_function(&self, function_name: &str, payload: Multipart, min_replicas: usize, target: &DeployTarget) -> Result<Deployment, FaasError> {
+        self.check_accepting()?;
+        let package = FunctionPackage::from_multipart(payload).await?;
+        self.deploy_to(package, Some(function_name), min_replicas, target).await
+    }
+
+    /// Deploy the package on each target node, this one or other peers. A failure on one node
+    /// does not stop the deployment on the next ones, the failures are returned with the nodes
+    /// providing the function. Fails only when no node deployed it.
+    async fn deploy_to(&self, package: FunctionPackage, function_name: Option<&str>, min_replicas: usize, target: &DeployTarget) -> Result<Deployment, FaasError> {
+        let peers = self.resolve_target(target).await?;
+        let mut function_name = function_name.map(str::to_string);
+        let mut providers = Vec::new();
+        let mut failures = Vec::new();
+        for peer in peers {
+            // The name generated by the first node is reused on the next ones.
+            match self.deploy_on(peer, package.clone(), function_name.as_deref(), min_replicas).await {
+                Ok(name) => {
+                    function_name = Some(name);
+                    providers.push(peer);
+                }
+                Err(e) => failures.push((peer, e)),
             }
         }
+        match function_name {
+            Some(function) if !providers.is_empty() => Ok(Deployment { function, providers, failures }),
+            _ => Err(failures.into_iter().next().map(|(_, e)| e)
+                .unwrap_or_else(|| FaasError::Internal("Placement chose no node".to_string()))),
+        }
+    }
 
-        // Start providing the function name to the network.    
-        network_client.start_providing(function_name.clone()).await;
+    /// Deploy the package on the given node, returning the function name.
+    async fn deploy_on(&self, peer: PeerId, package: FunctionPackage, function_name: Option<&str>, min_replicas: usize) -> Result<String, FaasError> {
+        if peer == self.peer_id {
+            return self.deploy_local(package, function_name, min_replicas).await;
+        }
+        let request = DeployRequest {
+            function: function_name.map(str::to_string),
+            package,
+            min_replicas,
+        };
+        match self.nc.request_deploy(peer, request).await? {
+            DeployResponse::Deployed { function } => {
+                info!("Function {} deployed on peer {:?}", function, peer);
+                self.nc.invalidate_providers(&function).await;
+                Ok(function)
+            }
+            DeployResponse::Failed { kind, message } => {
+                error!("Failed to deploy function on peer {:?}: {}", peer, message);
+                Err(FaasError::from_kind(&kind, message))
+            }
+        }
+    }
 
-        Ok(function_name)
+    /// Nodes to deploy on for the target, chosen by the placement engine among this node
+    /// and the peers with a live load report unless a peer is given.
+    async fn resolve_target(&self, target: &DeployTarget) -> Result<Vec<PeerId>, FaasError> {
+        match target {
+            DeployTarget::Local => Ok(vec![self.peer_id]),
+            DeployTarget::Peer(peer) => Ok(vec![*peer]),
+            DeployTarget::Placement(spec) => {
+                let mut nodes: Vec<(PeerId, LoadReport)> = self.nc.peer_loads().await
+                    .iter()
+                    .map(|(peer, report)| (*peer, report.clone()))
+                    .collect();
+                nodes.push((self.peer_id, self.load_report().await));
+                let peers = spec.place(nodes)?;
+                info!("Placement {:?} chose nodes {:?}", spec, peers);
+                Ok(peers)
+            }
+        }
     }
 
-    pub(crate) async fn deploy_known_function(&self, function_name: &String, payload: Multipart) -> Result<(), Box<dyn Error>> {
-        let network_client = &self.nc;
+    /// Deploy the package on the local OpenFaaS and provide the function, returning its name.
+    async fn deploy_local(&self, package: FunctionPackage, function_name: Option<&str>, min_replicas: usize) -> Result<String, FaasError> {
+        let function_name = self.deploy_openfaas(package, function_name, min_replicas).await?;
 
-        // Deploy the function to openfaas
-        let deployment_result = self.deploy_openfaas(payload, Some(function_name)).await;
-        
-        match deployment_result {
-            Ok(_) => (),
+        // Start providing the function name to the network.
+        self.nc.start_providing(function_name.clone()).await?;
+
+        Ok(function_name)
+    }
+
+    /// Deploy a function sent by another peer, if this node accepts remote deployments and the
+    /// inbound policy allows the peer to invoke the function.
+    pub(crate) async fn handle_deploy_request(&self, peer: PeerId, request: DeployRequest, channel: ResponseChannel<DeployResponse>) {
+        info!("Peer {:?} asked to deploy function {:?}", peer, request.function);
+        let result = if !self.accept_remote_deploy {
+            Err(FaasError::Forbidden("Node does not accept deployments from other peers".to_string()))
+        } else {
+            match self.check_accepting().and_then(|()| self.inbound_policy.check(&peer, request.function.as_deref().unwrap_or_default())) {
+                Ok(()) => self.deploy_local(request.package, request.function.as_deref(), request.min_replicas).await,
+                Err(e) => Err(e),
+            }
+        };
+        let response = match result {
+            Ok(function) => DeployResponse::Deployed { function },
             Err(e) => {
-                error!("Failed to deploy function: {:?}", e);
-                return Err(Box::new(io::Error::new(io::ErrorKind::Other, "Failed to deploy function")));
+                warn!("Rejected deployment from peer {:?}: {:?}", peer, e);
+                DeployResponse::Failed {
+                    kind: e.kind().to_string(),
+                    message: e.message().to_string(),
+                }
+            }
+        };
+        if let Err(e) = self.nc.respond_deploy(response, channel).await {
+            error!("Failed to answer deploy request: {:?}", e);
+        }
+    }
+
+    /// Fetch the package of a function from one of its providers, the nearest first, and deploy it
+    /// on this node. With a digest, only the package with this digest is accepted, so that providers
+    /// cannot send another package. Without, the latest package of the provider is checked against
+    /// the digest it sends, which only detects corrupted transfers. Returns the digest of the
+    /// deployed package.
+    pub(crate) async fn replicate_function(&self, function_name: &str, digest: Option<String>) -> Result<String, FaasError> {
+        self.check_accepting()?;
+        let mut providers: Vec<PeerId> = self.nc.get_providers(function_name.to_string()).await
+            .into_iter()
+            .filter(|provider| *provider != self.peer_id)
+            .collect();
+        if providers.is_empty() {
+            return Err(FaasError::NoProviders(format!("No other providers found for function {}", function_name)));
+        }
+        let latencies = self.nc.peer_latencies().await;
+        providers.sort_by_key(|provider| latencies.get(provider).unwrap_or(Duration::MAX));
+
+        let mut last_error = None;
+        for provider in providers {
+            match self.nc.request_package(provider, function_name.to_string(), digest.clone()).await {
+                Ok(PackageResponse::Found { digest: found, package, min_replicas })
+                    if package.digest() == found && digest.as_ref().is_none_or(|digest| *digest == found) =>
+                {
+                    info!("Fetched package {} of function {} from peer {:?}", found, function_name, provider);
+                    return self.deploy_package(function_name, package, min_replicas).await;
+                }
+                Ok(PackageResponse::Found { digest: found, .. }) => {
+                    warn!("Package {} of function {} from peer {:?} does not match digest {:?}", found, function_name, provider, digest);
+                    last_error = Some(FaasError::ProviderFailure(format!("Package of function {} from peer {} is corrupted", function_name, provider)));
+                }
+                Ok(PackageResponse::NotFound) => {
+                    last_error = Some(FaasError::NotFound(format!("Peer {} has no package of function {}", provider, function_name)));
+                }
+                Ok(PackageResponse::Forbidden) => {
+                    last_error = Some(FaasError::Forbidden(format!("Peer {} does not share the package of function {}", provider, function_name)));
+                }
+                Err(e) => {
+                    warn!("Failed to fetch package of function {} from peer {:?}: {:?}", function_name, provider, e);
+                    last_error = Some(e);
+                }
             }
         }
+        Err(last_error.unwrap_or_else(|| FaasError::NoProviders(format!("No providers found for function {}", function_name))))
+    }
 
-        // Start providing the function name to the network.    
-        network_client.start_providing(function_name.clone()).await;
+    /// Deploy the package on the local OpenFaaS and provide the function.
+    async fn deploy_package(&self, function_name: &str, package: FunctionPackage, min_replicas: usize) -> Result<String, FaasError> {
+        let digest = package.digest();
+        self.deploy_local(package, Some(function_name), min_replicas).await?;
+        Ok(digest)
+    }
 
+    /// Move a function provided by this node to another node without failing invocations: deploy it
+    /// on the target, wait until the target provides it and is healthy, withdraw the provider record
+    /// of this node, then wait for the invocations in progress here before removing the function.
+    /// Each wait gives up after the timeout. Returns the node the function moved to.
+    pub(crate) async fn migrate_function(&self, function_name: &str, target: &DeployTarget, timeout: Duration) -> Result<PeerId, FaasError> {
+        self.check_accepting()?;
+        if !self.provides(function_name).await {
+            return Err(FaasError::NotFound(format!("Function {} is not provided by this node", function_name)));
+        }
+        let Some((digest, package)) = self.package_store.get(function_name)? else {
+            return Err(FaasError::NotFound(format!("No package of function {} on this node", function_name)));
+        };
+        let target = self.resolve_migration_target(target).await?;
+        info!("Migrating function {} with package {} to peer {:?}", function_name, digest, target);
+
+        let min_replicas = self.package_store.min_replicas(function_name);
+        self.deploy_on(target, package, Some(function_name), min_replicas).await?;
+        self.wait_for_provider(function_name, target, timeout).await?;
+
+        // Peers still sending invocations here, from their cached providers or the records left on
+        // other DHT nodes, are answered that the function is not provided and fail over to the target.
+        self.nc.stop_providing(function_name.to_string()).await?;
+        info!("Function {} is provided by peer {:?}, draining it on this node", function_name, target);
+        sleep(self.nc.provider_cache_ttl().await.min(timeout)).await;
+        self.retire_function(function_name, timeout).await?;
+        info!("Function {} migrated to peer {:?}", function_name, target);
+        Ok(target)
+    }
+
+    /// Remove a function no longer provided by this node from OpenFaaS, once its invocations in
+    /// progress finish or the timeout elapses.
+    async fn retire_function(&self, function_name: &str, timeout: Duration) -> Result<(), FaasError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let in_progress = self.demand.lock().unwrap_or_else(PoisonError::into_inner).in_progress(function_name);
+            if in_progress == 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                warn!("Removing function {} with {} invocations in progress", function_name, in_progress);
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        self.ofc.remove_function(function_name).await?;
+        self.replicas.lock().unwrap_or_else(PoisonError::into_inner).remove(function_name);
+        self.demand.lock().unwrap_or_else(PoisonError::into_inner).remove(function_name);
         Ok(())
     }
 
-    async fn function_request(&self, providers: HashSet<PeerId>, peer_id: &PeerId, name: &String, method: &String, body: &Option<Vec<u8>>, requests_in_progress: &Arc<Mutex<RequestsInProgress>>, openfaas_client: &Arc<OpenFaasClient>, network_client: &Arc<NetworkClient>) -> Result<OpenFaaSResponse, Box<dyn Error>> {
+    /// Node to migrate a function to: the given peer, or the node chosen by the placement engine
+    /// among the peers with a live load report.
+    async fn resolve_migration_target(&self, target: &DeployTarget) -> Result<PeerId, FaasError> {
+        let target = match target {
+            DeployTarget::Local => None,
+            DeployTarget::Peer(peer) => Some(*peer),
+            DeployTarget::Placement(spec) => {
+                let nodes = self.nc.peer_loads().await
+                    .iter()
+                    .filter(|(peer, _)| **peer != self.peer_id)
+                    .map(|(peer, report)| (*peer, report.clone()))
+                    .collect();
+                let spec = PlacementSpec { replicas: 1, ..spec.clone() };
+                spec.place(nodes)?.first().copied()
+            }
+        };
+        match target {
+            Some(peer) if peer != self.peer_id => Ok(peer),
+            _ => Err(FaasError::BadInput("The function must be migrated to another node".to_string())),
+        }
+    }
+
+    /// Wait until the peer is listed among the providers of the function, with a live load report
+    /// showing it provides the function and is not saturated.
+    async fn wait_for_provider(&self, function_name: &str, peer: PeerId, timeout: Duration) -> Result<(), FaasError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            self.nc.invalidate_providers(function_name).await;
+            let providing = self.nc.get_providers(function_name.to_string()).await.contains(&peer);
+            let healthy = self.nc.peer_loads().await
+                .get(&peer)
+                .is_some_and(|report| !report.is_saturated() && report.warm_functions.iter().any(|warm| warm == function_name));
+            if providing && healthy {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(FaasError::Timeout(format!(
+                    "Peer {} did not become a healthy provider of function {} within {:?}, the function is still provided by this node",
+                    peer, function_name, timeout
+                )));
+            }
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    /// Send the package of a function to a peer, if the inbound policy allows the peer to invoke it.
+    pub(crate) async fn handle_package_request(&self, peer: PeerId, request: PackageRequest, channel: ResponseChannel<PackageResponse>) {
+        info!("Package of function {:?} requested by peer {:?}", request.function, peer);
+        let response = if self.inbound_policy.check(&peer, &request.function).is_err() {
+            warn!("Rejected package request {:?} from peer {:?}", request.function, peer);
+            PackageResponse::Forbidden
+        } else {
+            let package = match request.digest {
+                Some(digest) => self.package_store.get_by_digest(&digest).map(|package| package.map(|package| (digest, package))),
+                None => self.package_store.get(&request.function),
+            };
+            match package {
+                Ok(Some((digest, package))) => PackageResponse::Found {
+                    digest,
+                    package,
+                    min_replicas: self.package_store.min_replicas(&request.function),
+                },
+                Ok(None) => PackageResponse::NotFound,
+                Err(e) => {
+                    error!("Failed to read package of function {:?}: {:?}", request.function, e);
+                    PackageResponse::NotFound
+                }
+            }
+        };
+        if let Err(e) = self.nc.respond_package(response, channel).await {
+            error!("Failed to respond with package: {:?}", e);
+        }
+    }
+
+    pub(crate) async fn metrics(&self) -> NodeMetrics {
+        NodeMetrics {
+            provider_cache: self.nc.provider_cache_stats().await,
+            peer_rtt_ms: self.nc.peer_latencies().await
+                .iter()
+                .map(|(peer, rtt)| (peer.to_base58(), rtt.as_millis() as u64))
+                .collect(),
+            peer_loads: self.nc.peer_loads().await
+                .iter()
+                .map(|(peer, report)| (peer.to_base58(), report.clone()))
+                .collect(),
+            compression: self.nc.compression_stats(),
+        }
+    }
+
+    async fn function_request(&self, providers: HashSet<PeerId>, peer_id: &PeerId, name: &String, method: &str, body: &Option<Vec<u8>>, deadline: Option<Instant>) -> Result<OpenFaaSResponse, FaasError> {
+        let network_client = &self.nc;
+        let openfaas_client = &self.ofc;
+        let requests_in_progress = &self.rp;
         let function_response_status;
         let function_response_body;
         
@@ -269,20 +1198,22 @@ impl FunctionsService {
                 let mut rp_instance = requests_in_progress_clone.lock().await;
                 rp_instance.push_req(peer_id, false);
             }
-            let resp = openfaas_client.request_function(&name, &method, body.clone()).await;
+            let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            let demand = self.track_demand(name.split(['/', '?']).next().unwrap_or_default());
+            let resp = openfaas_client.request_function(name, method, body.clone(), timeout).await;
+            drop(demand);
             {
                 let mut rp_instance = requests_in_progress_clone.lock().await;
                 rp_instance.pop_req(peer_id, false);
             }
             match resp {
                 Ok(resp) => {
-                    function_response_status = resp.status().as_u16();
-                    let resp_body = resp.bytes().await.unwrap().to_vec();
-                    function_response_body = resp_body;
+                    function_response_status = resp.status;
+                    function_response_body = resp.body;
                 }
                 Err(err) => {
                     error!("Failed to get response from function {}: {:?}", name, err);
-                    return Err(Box::new(io::Error::new(io::ErrorKind::Other, "Failed to get response from function")));
+                    return Err(err);
                 }
             }
         }
@@ -290,7 +1221,8 @@ impl FunctionsService {
             let requests = providers.into_iter().map(|p| {
                 let network_client = network_client.clone();
                 let name = name.clone();
-                let method = method.clone();
+                let function_name = name.split(['/', '?']).next().unwrap_or_default().to_string();
+                let method = method.to_string();
                 let body = body.clone();
                 let requests_in_progress_clone = Arc::clone(requests_in_progress);
                 async move { 
@@ -298,12 +1230,23 @@ impl FunctionsService {
                         let mut rp_instance = requests_in_progress_clone.lock().await;
                         rp_instance.push_req(&p, false);
                     }
-                    let response = network_client.request_function(p, name, method, body).await;
+                    let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+                    let response = network_client.request_function(p, name, method, body, timeout).await;
                     {
                         let mut rp_instance = requests_in_progress_clone.lock().await;
                         rp_instance.pop_req(&p, false);
                     }
-                    response
+                    // A provider that did not run the function lets the other providers answer.
+                    match response.map(|response| (fail_over_error(&p, &response), response)) {
+                        Ok((Some(e), _)) => {
+                            if let FaasError::NotProvided(_) | FaasError::Unavailable(_) = e {
+                                network_client.invalidate_provider(&function_name, &p).await;
+                            }
+                            Err(e)
+                        }
+                        Ok((None, response)) => Ok(response),
+                        Err(e) => Err(e),
+                    }
                  }.boxed()
             });
     
@@ -313,12 +1256,24 @@ impl FunctionsService {
             
             match function_response {
                 Ok(function_response) => {
-                    function_response_body = function_response.0.1;
-                    function_response_status = function_response.0.0;
+                    function_response_body = function_response.0.body;
+                    function_response_status = function_response.0.status;
                 },
+                Err(FaasError::Busy(message)) => {
+                    error!("Providers are busy: {:?}", message);
+                    return Err(FaasError::Busy(format!("All providers are busy: {}", message)));
+                }
+                Err(FaasError::NotProvided(message)) => {
+                    error!("None of the providers provides the function anymore: {:?}", message);
+                    return Err(FaasError::NoProviders(format!("None of the providers provides the function anymore: {}", message)));
+                }
+                Err(FaasError::Timeout(message)) => {
+                    error!("None of the providers responded in time: {:?}", message);
+                    return Err(FaasError::Timeout(format!("None of the providers responded in time: {}", message)));
+                }
                 Err(e) => {
                     error!("None of the providers responded: {:?}", e);
-                    return Err(Box::new(io::Error::new(io::ErrorKind::NotFound,"None of the providers responded")));
+                    return Err(FaasError::ProviderFailure(format!("None of the providers responded: {}", e.message())));
                 }
             };
         }
@@ -331,19 +1286,160 @@ impl FunctionsService {
         Ok(openfaas_response)
     }
 
-    async fn deploy_openfaas(&self, payload: Multipart, function_name: Option<&str>) -> Result<String, actix_web::Error> {
+    /// Deploy the package on the local OpenFaaS and keep it for other nodes to replicate the function.
+    async fn deploy_openfaas(&self, package: FunctionPackage, function_name: Option<&str>, min_replicas: usize) -> Result<String, FaasError> {
         let openfaas_client = &self.ofc;
-        let deployment_result = openfaas_client.deploy_function(payload, function_name).await;
-        let function_name;
-        match deployment_result {
-            Ok(fun_name) => function_name = fun_name,
+        let deployment_result = openfaas_client.deploy_function(&package, function_name).await;
+        let function_name = match deployment_result {
+            Ok(fun_name) => fun_name,
             Err(e) => {
                 error!("Failed to deploy function: {:?}", e);
-                return Err(actix_web::error::ErrorInternalServerError("Failed to deploy function"));
+                return Err(e);
             }
+        };
+        if let Err(e) = self.package_store.insert(&function_name, &package, min_replicas) {
+            error!("Failed to store package of function {}: {:?}", function_name, e);
         }
         Ok(function_name)
     }
 
 }
 
+/// Unregisters an in-flight invocation, also when the request executing it is dropped,
+/// so that waiting requests retry instead of hanging.
+struct InFlightGuard<'a> {
+    in_flight: &'a StdMutex<InFlightInvocations>,
+    key: InvocationKey,
+    done: bool,
+}
+
+impl InFlightGuard<'_> {
+    fn complete(&mut self) -> Vec<oneshot::Sender<SharedInvocationResult>> {
+        self.done = true;
+        self.in_flight.lock().unwrap_or_else(PoisonError::into_inner).complete(&self.key)
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.in_flight.lock().unwrap_or_else(PoisonError::into_inner).complete(&self.key);
+        }
+    }
+}
+
+/// Unregisters a request to a provider from the requests in progress once it is dropped.
+struct RequestInProgressGuard {
+    rp: Arc<Mutex<RequestsInProgress>>,
+    provider: PeerId,
+}
+
+/// Response body of a provider, keeping the request to it in progress until the body is dropped.
+struct InProgressBody {
+    body: BodyStream,
+    _guard: RequestInProgressGuard,
+}
+
+impl futures::Stream for InProgressBody {
+    type Item = Result<Bytes, FaasError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.body.poll_next_unpin(cx)
+    }
+}
+
+/// Counts an invocation waiting for a free execution slot until dropped.
+struct InboundQueueGuard<'a> {
+    queued: &'a AtomicUsize,
+}
+
+impl Drop for InboundQueueGuard<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Marks the end of an execution counted in the demand for a function.
+struct DemandGuard<'a> {
+    demand: &'a StdMutex<FunctionDemand>,
+    function_name: String,
+}
+
+impl Drop for DemandGuard<'_> {
+    fn drop(&mut self) {
+        self.demand.lock().unwrap_or_else(PoisonError::into_inner).finish(&self.function_name);
+    }
+}
+
+impl Drop for RequestInProgressGuard {
+    fn drop(&mut self) {
+        let rp = Arc::clone(&self.rp);
+        let provider = self.provider;
+        spawn(async move {
+            rp.lock().await.pop_req(&provider, false);
+        });
+    }
+}
+
+/// Answer a function stream with the error, as a response header and the error body.
+async fn respond_stream_error(writer: ResponseWriter, err: FaasError) -> Result<(), FaasError> {
+    let response = FunctionResponse {
+        error_kind: Some(err.kind().to_string()),
+        ..FunctionResponse::new(err.status_code().as_u16(), Vec::new())
+    };
+    let body = futures::stream::once(async move { Ok(Bytes::from(err.body().to_string())) }).boxed();
+    writer.respond(response, body).await
+}
+
+/// Error of a provider that did not run the function, so that the other providers answer instead:
+/// the provider is busy or draining, or no longer provides the function. Other responses, such as
+/// a 404 of the function itself, are returned to the caller unchanged.
+fn fail_over_error(provider: &PeerId, response: &FunctionResponse) -> Option<FaasError> {
+    let kind = match &response.error_kind {
+        Some(kind) => Some(kind.clone()),
+        // Providers speaking the first version of the protocol only send the error in the body.
+        None if response.status == StatusCode::SERVICE_UNAVAILABLE.as_u16() => serde_json::from_slice::<Value>(&response.body)
+            .ok()
+            .and_then(|body| body["error"]["kind"].as_str().map(str::to_string)),
+        None => None,
+    };
+    match kind.as_deref() {
+        Some("busy") => Some(FaasError::Busy(format!("Provider {} is busy", provider))),
+        Some("not_provided") => Some(FaasError::NotProvided(format!("Provider {} no longer provides the function", provider))),
+        Some("unavailable") => Some(FaasError::Unavailable(format!("Provider {} is draining", provider))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_response(status: StatusCode, kind: &str) -> FunctionResponse {
+        FunctionResponse::new(status.as_u16(), FaasError::from_kind(kind, String::new()).body().to_string().into_bytes())
+    }
+
+    #[test]
+    fn providers_that_did_not_run_the_function_fail_over() {
+        let provider = PeerId::random();
+        let mut response = FunctionResponse::new(StatusCode::SERVICE_UNAVAILABLE.as_u16(), Vec::new());
+        response.error_kind = Some("not_provided".to_string());
+        assert!(matches!(fail_over_error(&provider, &response), Some(FaasError::NotProvided(_))));
+
+        // Providers speaking the first version of the protocol send the kind in the body only.
+        let response = error_response(StatusCode::SERVICE_UNAVAILABLE, "busy");
+        assert!(matches!(fail_over_error(&provider, &response), Some(FaasError::Busy(_))));
+    }
+
+    #[test]
+    fn function_errors_are_passed_through() {
+        let provider = PeerId::random();
+        // The function itself answers 404.
+        let response = FunctionResponse::new(StatusCode::NOT_FOUND.as_u16(), b"no such item".to_vec());
+        assert!(fail_over_error(&provider, &response).is_none());
+
+        // Only 503 responses of the first protocol version are read for an error kind.
+        let response = error_response(StatusCode::NOT_FOUND, "not_provided");
+        assert!(fail_over_error(&provider, &response).is_none());
+    }
+}
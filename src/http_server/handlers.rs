@@ -1,35 +1,136 @@
 use actix_web::http::StatusCode;
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use actix_multipart::Multipart;
 use reqwest::Method;
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt};
 
 use serde::Deserialize;
 use serde_json::Value;
 use serde_json::json;
 
 use log::{info, error};
+use std::time::Duration;
 
+use crate::error::FaasError;
+use crate::functions_service::Deployment;
 use crate::http_server::server::AppState;
 use crate::model::serialize_body;
+use crate::placement::{DeployTarget, PlacementSpec};
 use crate::model::detect_and_parse_body;
 
+const TIMEOUT_HEADER: &str = "X-Timeout-Ms";
+const METHOD_HEADER: &str = "X-Function-Method";
+const MIGRATION_TIMEOUT_SECS: u64 = 120;
+
+/// Milliseconds the client waits for the function, from the X-Timeout-Ms header.
+fn timeout_header(req: &HttpRequest) -> Result<Option<u64>, FaasError> {
+    match req.headers().get(TIMEOUT_HEADER) {
+        Some(header) => header.to_str().ok().and_then(|value| value.parse::<u64>().ok())
+            .map(Some)
+            .ok_or_else(|| FaasError::BadInput(format!("Invalid {} header", TIMEOUT_HEADER))),
+        None => Ok(None),
+    }
+}
+
 #[derive(Deserialize)]
 pub struct AnycallBody {
     http_method: String,
     body: Option<Value>,
-    path_and_query: Option<String>
+    path_and_query: Option<String>,
+    // Milliseconds the client waits for the function, also accepted in the X-Timeout-Ms header
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct DeployQuery {
+    // Number of providers the function must keep, 1 by default
+    min_replicas: Option<usize>,
+    // Node to deploy on: a peer ID, key=value labels or auto. This node by default
+    target: Option<String>,
+    // Placement of the function, as comma-separated key=value or key!=value conditions on the node labels
+    constraints: Option<String>,
+    prefer: Option<String>,
+    // Label whose values the nodes chosen must differ in
+    spread: Option<String>,
+    // Number of nodes to deploy on
+    replicas: Option<usize>,
+}
+
+impl DeployQuery {
+    fn has_placement(&self) -> bool {
+        self.constraints.is_some() || self.prefer.is_some() || self.spread.is_some() || self.replicas.is_some()
+    }
+
+    /// Whether the function is deployed on other nodes than this one.
+    fn is_targeted(&self) -> bool {
+        self.target.is_some() || self.has_placement()
+    }
+
+    fn target(&self) -> Result<DeployTarget, FaasError> {
+        let target = match &self.target {
+            Some(target) => target.parse().map_err(FaasError::BadInput)?,
+            None if self.has_placement() => DeployTarget::Placement(PlacementSpec::default()),
+            None => DeployTarget::Local,
+        };
+        if !self.has_placement() {
+            return Ok(target);
+        }
+        let DeployTarget::Placement(mut spec) = target else {
+            return Err(FaasError::BadInput("Placement options require target auto or a label selector".to_string()));
+        };
+        if let Some(constraints) = &self.constraints {
+            spec.constraints.extend(PlacementSpec::parse_matches(constraints).map_err(FaasError::BadInput)?);
+        }
+        if let Some(preferences) = &self.prefer {
+            spec.preferences.extend(PlacementSpec::parse_matches(preferences).map_err(FaasError::BadInput)?);
+        }
+        if self.spread.is_some() {
+            spec.spread = self.spread.clone();
+        }
+        match self.replicas {
+            Some(0) => return Err(FaasError::BadInput("replicas must be at least 1".to_string())),
+            Some(replicas) => spec.replicas = replicas,
+            None => (),
+        }
+        Ok(DeployTarget::Placement(spec))
+    }
+
+    fn min_replicas(&self) -> Result<usize, FaasError> {
+        match self.min_replicas {
+            Some(0) => Err(FaasError::BadInput("min_replicas must be at least 1".to_string())),
+            Some(min_replicas) => Ok(min_replicas),
+            None => Ok(1),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ReplicateQuery {
+    // Digest of the package to deploy, the latest package of the providers by default
+    digest: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MigrateQuery {
+    // Node to move the function to: a peer ID, key=value labels or auto
+    target: String,
+    // Seconds to wait for the target to provide the function, then for the invocations in progress
+    timeout_secs: Option<u64>,
 }
 
 #[derive(Deserialize)]
 pub struct ManycallBody {
     // Array of objects
-    items: Value
+    items: Value,
+    // Milliseconds the client waits for the invocations, also accepted in the X-Timeout-Ms header
+    timeout_ms: Option<u64>,
 }
 
-pub async fn execute_function(data: web::Data<AppState>, path: web::Path<String>, req_body: web::Json<AnycallBody>) -> impl Responder {
+pub async fn execute_function(data: web::Data<AppState>, path: web::Path<String>, req: HttpRequest, req_body: web::Json<AnycallBody>) -> impl Responder {
     let name = path.into_inner();
     if name.is_empty() {
-        return Err(actix_web::error::ErrorBadRequest("Function name is empty"));
+        return Err(FaasError::BadInput("Function name is empty".to_string()));
     }
     
     let functions_service = &data.fs;
@@ -38,33 +139,79 @@ pub async fn execute_function(data: web::Data<AppState>, path: web::Path<String>
     
     match Method::from_bytes(method.as_bytes()) {
         Ok(_) => (),
-        Err(_) => return Err(actix_web::error::ErrorBadRequest("Invalid HTTP method"))
+        Err(_) => return Err(FaasError::BadInput(format!("Invalid HTTP method: {}", method)))
     };
 
     let body_field = &req_body.body;
-    let body = match body_field {
-        Some(b) => Some(b.to_string().into_bytes()),
-        None => None
-    };
+    let body = body_field.as_ref().map(|b| b.to_string().into_bytes());
 
     let path_and_query_field = &req_body.path_and_query;
 
-    let function_response_result = functions_service.execute_function(name, method, body, path_and_query_field).await;
+    let timeout_ms = match req_body.timeout_ms {
+        Some(timeout_ms) => Some(timeout_ms),
+        None => timeout_header(&req)?,
+    };
+    let timeout = timeout_ms.map(Duration::from_millis);
+
+    let function_response_result = functions_service.execute_function(name, method, body, path_and_query_field, timeout).await;
 
     match function_response_result {
         Ok(function_response_result) => {
             let s = serialize_body(detect_and_parse_body(function_response_result.body)).to_string();
             info!("Response received in handler, body: {:?}", s);
-            return Ok(HttpResponse::build(StatusCode::from_u16(function_response_result.status).unwrap()).body(s));
+            let status = StatusCode::from_u16(function_response_result.status)
+                .map_err(|_| FaasError::ProviderFailure(format!("Invalid status code from function: {}", function_response_result.status)))?;
+            Ok(HttpResponse::build(status).body(s))
         },
-        Err(e) => return Err(actix_web::error::ErrorInternalServerError(e))
+        Err(e) => Err(e)
+    }
+}
+
+pub async fn execute_function_stream(data: web::Data<AppState>, path: web::Path<String>, req: HttpRequest, mut payload: web::Payload) -> Result<HttpResponse, FaasError> {
+    let name = path.into_inner();
+    if name.is_empty() {
+        return Err(FaasError::BadInput("Function name is empty".to_string()));
     }
+
+    let functions_service = &data.fs;
+
+    let method = match req.headers().get(METHOD_HEADER) {
+        Some(header) => header.to_str()
+            .map_err(|_| FaasError::BadInput(format!("Invalid {} header", METHOD_HEADER)))?
+            .to_uppercase(),
+        None => "POST".to_string(),
+    };
+    if Method::from_bytes(method.as_bytes()).is_err() {
+        return Err(FaasError::BadInput(format!("Invalid HTTP method: {}", method)));
+    }
+    // The query of the request is passed to the function.
+    let path_and_query = req.uri().query().map(|query| format!("?{}", query));
+    let timeout = timeout_header(&req)?.map(Duration::from_millis);
+
+    // The payload is bound to the worker thread, forward its chunks through a channel.
+    let (mut sender, receiver) = mpsc::channel(8);
+    actix_web::rt::spawn(async move {
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|e| FaasError::BadInput(format!("Failed to read request body: {}", e)));
+            let failed = chunk.is_err();
+            if sender.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let (status, body) = functions_service
+        .execute_function_stream(name, &method, path_and_query, receiver.boxed(), timeout)
+        .await?;
+    let status = StatusCode::from_u16(status)
+        .map_err(|_| FaasError::ProviderFailure(format!("Invalid status code from function: {}", status)))?;
+    Ok(HttpResponse::build(status).streaming(body))
 }
 
-pub async fn execute_function_manycall(data: web::Data<AppState>, path: web::Path<String>, req_body: web::Json<ManycallBody>) -> impl Responder {
+pub async fn execute_function_manycall(data: web::Data<AppState>, path: web::Path<String>, req: HttpRequest, req_body: web::Json<ManycallBody>) -> impl Responder {
     let name = path.into_inner();
     if name.is_empty() {
-        return Err(actix_web::error::ErrorBadRequest("Function name is empty"));
+        return Err(FaasError::BadInput("Function name is empty".to_string()));
     }
         
     let functions_service = &data.fs;
@@ -74,16 +221,22 @@ pub async fn execute_function_manycall(data: web::Data<AppState>, path: web::Pat
             // Handle the case where the item is an array
             array.to_owned()
         }
-        _ => return Ok(HttpResponse::BadRequest().body("Invalid request body. Field items must be an array")),
+        _ => return Err(FaasError::BadInput("Invalid request body. Field items must be an array".to_string())),
+    };
+
+    let timeout_ms = match req_body.timeout_ms {
+        Some(timeout_ms) => Some(timeout_ms),
+        None => timeout_header(&req)?,
     };
+    let timeout = timeout_ms.map(Duration::from_millis);
 
-    let response = functions_service.execute_function_manycall(&name, items).await;
+    let response = functions_service.execute_function_manycall(&name, items, timeout).await;
 
     let response = match response {
         Ok(response) => response,
         Err(e) => {
             error!("Failed to execute manycall: {:?}", e);
-            return Err(actix_web::error::ErrorInternalServerError("Failed to execute manycall"));
+            return Err(e);
         }
     };
     let body = json!({
@@ -93,37 +246,112 @@ pub async fn execute_function_manycall(data: web::Data<AppState>, path: web::Pat
     Ok(HttpResponse::Ok().json(body))
 }
 
-pub async fn deploy_function(data: web::Data<AppState>, payload: Multipart) -> impl Responder {
+pub async fn deploy_function(data: web::Data<AppState>, query: web::Query<DeployQuery>, payload: Multipart) -> impl Responder {
     let functions_service = &data.fs;
+    let min_replicas = query.min_replicas()?;
+    let target = query.target()?;
 
-    let function_name = functions_service.deploy_function(payload).await;
+    let deployment_result = functions_service.deploy_function(payload, min_replicas, &target).await;
     
-    match function_name {
-        Ok(function_name) => return Ok(HttpResponse::Ok().body(function_name)),
+    match deployment_result {
+        // Deployments to another node tell which node provides the function.
+        Ok(deployment) if query.is_targeted() => Ok(deployment_response(&deployment)),
+        Ok(deployment) => Ok(HttpResponse::Ok().body(deployment.function)),
         Err(e) => {
             error!("Failed to deploy function: {:?}", e);
-            return Err(actix_web::error::ErrorInternalServerError("Failed to deploy function"));
+            Err(e)
         }
     }
 }
 
-pub async fn deploy_known_function(data: web::Data<AppState>, payload: Multipart, path: web::Path<String>) -> impl Responder {
+pub async fn deploy_known_function(data: web::Data<AppState>, query: web::Query<DeployQuery>, payload: Multipart, path: web::Path<String>) -> impl Responder {
     let function_name = path.into_inner();
     if function_name.is_empty() {
-        return Err(actix_web::error::ErrorBadRequest("Function name is empty"));
+        return Err(FaasError::BadInput("Function name is empty".to_string()));
     }
+    let min_replicas = query.min_replicas()?;
+    let target = query.target()?;
     
     let functions_service = &data.fs;
     
-    let deployment_result = functions_service.deploy_known_function(&function_name, payload).await;
+    let deployment_result = functions_service.deploy_known_function(&function_name, payload, min_replicas, &target).await;
 
     match deployment_result {
-        Ok(_) => return Ok(HttpResponse::Ok().body(function_name.clone())),
+        Ok(deployment) if query.is_targeted() => Ok(deployment_response(&deployment)),
+        Ok(_) => Ok(HttpResponse::Ok().body(function_name.clone())),
         Err(e) => {
             error!("Failed to deploy function: {:?}", e);
-            return Err(actix_web::error::ErrorInternalServerError("Failed to deploy function"));
+            Err(e)
         }
     }
 }
 
+/// Nodes providing the function and nodes on which the deployment failed, answered with
+/// 207 Multi-Status when some failed so that the caller knows which nodes run the function.
+fn deployment_response(deployment: &Deployment) -> HttpResponse {
+    let providers: Vec<String> = deployment.providers.iter().map(|peer| peer.to_base58()).collect();
+    let failures: Vec<Value> = deployment.failures.iter()
+        .map(|(peer, e)| json!({
+            "peer": peer.to_base58(),
+            "error": e.body()["error"],
+        }))
+        .collect();
+    let status = if failures.is_empty() { StatusCode::OK } else { StatusCode::MULTI_STATUS };
+    HttpResponse::build(status).json(json!({
+        "function": deployment.function,
+        "provider": providers.first(),
+        "providers": providers,
+        "failures": failures
+    }))
+}
+
+pub async fn replicate_function(data: web::Data<AppState>, path: web::Path<String>, query: web::Query<ReplicateQuery>) -> Result<HttpResponse, FaasError> {
+    let function_name = path.into_inner();
+    let functions_service = &data.fs;
+
+    let digest = functions_service.replicate_function(&function_name, query.into_inner().digest).await.map_err(|e| {
+        error!("Failed to replicate function: {:?}", e);
+        e
+    })?;
 
+    Ok(HttpResponse::Ok().json(json!({
+        "function": function_name,
+        "digest": digest
+    })))
+}
+
+pub async fn migrate_function(data: web::Data<AppState>, path: web::Path<String>, query: web::Query<MigrateQuery>) -> Result<HttpResponse, FaasError> {
+    let function_name = path.into_inner();
+    let functions_service = &data.fs;
+    let target: DeployTarget = query.target.parse().map_err(FaasError::BadInput)?;
+    let timeout = Duration::from_secs(query.timeout_secs.unwrap_or(MIGRATION_TIMEOUT_SECS));
+
+    let provider = functions_service.migrate_function(&function_name, &target, timeout).await.map_err(|e| {
+        error!("Failed to migrate function: {:?}", e);
+        e
+    })?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "function": function_name,
+        "source": functions_service.peer_id.to_base58(),
+        "provider": provider.to_base58()
+    })))
+}
+
+pub async fn get_metrics(data: web::Data<AppState>) -> impl Responder {
+    let functions_service = &data.fs;
+
+    let metrics = functions_service.metrics().await;
+
+    HttpResponse::Ok().json(metrics)
+}
+
+pub async fn drain_node(data: web::Data<AppState>) -> impl Responder {
+    let functions_service = &data.fs;
+
+    functions_service.request_drain();
+
+    HttpResponse::Accepted().json(json!({
+        "draining": true
+    }))
+}
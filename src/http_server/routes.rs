@@ -1,6 +1,6 @@
 use actix_web::web;
 
-use crate::http_server::handlers::{execute_function, execute_function_manycall, deploy_function, deploy_known_function};
+use crate::http_server::handlers::{execute_function, execute_function_stream, execute_function_manycall, deploy_function, deploy_known_function, replicate_function, migrate_function, get_metrics, drain_node};
 
 pub fn routes(cfg: &mut web::ServiceConfig) {
     cfg
@@ -9,8 +9,13 @@ pub fn routes(cfg: &mut web::ServiceConfig) {
             .service(web::resource("/functions/{name}/executions")
                     .route(web::post().to(execute_function))
             )
+            .route("/functions/{name}/executions/stream", web::post().to(execute_function_stream))
             .route("/functions/{name}/executions/manycall", web::post().to(execute_function_manycall))
             .route("/functions/deployments", web::post().to(deploy_function))
             .route("/functions/deployments/{name}", web::put().to(deploy_known_function))
+            .route("/functions/{name}/replicate", web::post().to(replicate_function))
+            .route("/functions/{name}/migrate", web::post().to(migrate_function))
+            .route("/node/metrics", web::get().to(get_metrics))
+            .route("/node/drain", web::post().to(drain_node))
         );
 }
\ No newline at end of file
@@ -1,6 +1,8 @@
-use actix_web::{web, App, HttpServer, middleware};
+use actix_web::{web, App, HttpResponse, HttpServer, middleware};
+use actix_web::dev::Server;
 use std::sync::Arc;
 
+use crate::error::FaasError;
 use crate::functions_service::FunctionsService;
 use crate::http_server::routes::routes;
 
@@ -15,15 +17,21 @@ impl AppState {
     }
 }
 
-pub async fn run_http_server(app_state: AppState, port: u16) -> std::io::Result<()> {
+/// Bind the HTTP server. Signals are not handled by the server, the node drains and stops it on shutdown.
+pub fn run_http_server(app_state: AppState, port: u16) -> std::io::Result<Server> {
     let data = web::Data::new(app_state);
 
     let server = HttpServer::new(move || {
         App::new()
             .app_data(data.clone())
+            .app_data(web::JsonConfig::default()
+                .error_handler(|err, _| FaasError::BadInput(err.to_string()).into()))
             .wrap(middleware::Logger::default())
             .configure(routes)
+            .default_service(web::to(|| async {
+                Err::<HttpResponse, _>(FaasError::NotFound("Resource not found".to_string()))
+            }))
     });
 
-    server.bind(("0.0.0.0", port))?.run().await
+    Ok(server.disable_signals().bind(("0.0.0.0", port))?.run())
 }
\ No newline at end of file
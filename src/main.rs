@@ -1,26 +1,44 @@
+mod error;
 mod network;
 mod openfaas;
 mod model;
 use openfaas::OpenFaasClient;
 mod http_server;
 mod data_structures;
-use data_structures::RequestsInProgress;
 mod functions_service;
-use functions_service::FunctionsService;
+use functions_service::{AutoscaleConfig, FunctionsService, InboundLimits};
+mod package;
+mod placement;
+mod policy;
+mod protocol;
+mod streaming;
+mod system;
+use package::PackageStore;
+use policy::InboundPolicy;
+use protocol::Compression;
+use model::parse_label;
+use error::FaasError;
 
 use tokio::task::spawn;
-use tokio::sync::Mutex;
+use tokio::signal::ctrl_c;
+use tokio::signal::unix::{signal, Signal, SignalKind};
 
 use futures::StreamExt;
-use libp2p::{core::Multiaddr, multiaddr::Protocol};
+use libp2p::{core::Multiaddr, multiaddr::Protocol, pnet::PreSharedKey};
 
 use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use tracing_subscriber::EnvFilter;
 use clap::Parser;
 
 use log::{info, error};
+use actix_web::dev::ServerHandle;
+
+const BOOTSTRAP_DIAL_ATTEMPTS: u32 = 6;
 
 #[actix_web::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -29,31 +47,66 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .try_init();
 
     let opt = Opt::parse();
+    let inbound_policy = match &opt.inbound_policy {
+        Some(path) => InboundPolicy::from_file(path)?,
+        None => InboundPolicy::allow_all(),
+    };
+    if let Some(relay) = opt.relay.iter().find(|addr| !matches!(addr.iter().last(), Some(Protocol::P2p(_)))) {
+        return Err(format!("Expect relay multiaddr {} to contain peer ID.", relay).into());
+    }
     
+    let network_config = network::NetworkConfig {
+        secret_key_seed: opt.secret_key_seed,
+        provider_cache_ttl: Duration::from_secs(opt.provider_cache_ttl_secs),
+        enable_mdns: opt.mdns,
+        bootstrap_interval: Duration::from_secs(opt.bootstrap_interval_secs),
+        relays: opt.relay.clone(),
+        enable_relay_server: opt.relay_server,
+        pre_shared_key: opt.swarm_key.as_deref().map(read_swarm_key).transpose()?,
+        // Reports missing for a few periods belong to peers that left or are stuck.
+        load_report_ttl: Duration::from_secs(opt.load_report_interval_secs * 3),
+        max_connections: opt.max_connections,
+        max_connections_per_peer: opt.max_connections_per_peer,
+        request_timeout: Duration::from_secs(opt.request_timeout_secs),
+        compression: opt.compression,
+        compression_threshold: opt.compression_threshold,
+    };
     let (network_client, mut network_events, network_event_loop, peer_id) =
-        network::new(opt.secret_key_seed).await?;
+        network::new(network_config).await?;
     info!("Peer ID: {:?}", peer_id.to_base58());
 
     // Spawn the network task for it to run in the background.
     spawn(network_event_loop.run());
 
-    // Listen on provided address.
-    
-    network_client
-            .start_listening(opt.p2p_listen_address)
-            .await
-            .expect("Listening fail.");
+    // Listen on provided addresses, TCP or QUIC.
+    for addr in opt.p2p_listen_addresses {
+        network_client
+                .start_listening(addr)
+                .await?;
+    }
 
-    // In case the user provided an address of a peer on the CLI, dial it.
-    if let Some(addr) = opt.peer {
+    // In case the user provided addresses of bootstrap peers on the CLI or in a file, dial them.
+    let mut bootstrap_peers = opt.bootstrap;
+    if let Some(path) = &opt.bootstrap_file {
+        bootstrap_peers.extend(read_bootstrap_file(path)?);
+    }
+    for addr in bootstrap_peers {
         let Some(Protocol::P2p(peer_id)) = addr.iter().last() else {
-            return Err("Expect peer multiaddr to contain peer ID.".into());
+            return Err(format!("Expect peer multiaddr {} to contain peer ID.", addr).into());
         };
-        network_client
-            .dial(peer_id, addr)
-            .await
-            .expect("Dial fail.");
+        // Dial in the background, bootstrap peers may not be up yet.
+        spawn({
+            let network_client = network_client.clone();
+            async move {
+                if let Err(e) = network_client.dial_with_backoff(peer_id, addr.clone(), BOOTSTRAP_DIAL_ATTEMPTS).await {
+                    error!("Failed to dial bootstrap peer {:?}: {:?}", addr, e);
+                }
+            }
+        });
     }
+    // Keep the providers of the functions in use fresh in the background.
+    spawn(network_client.clone().run_provider_cache_refresh());
+
     // Arc allows for multiple ownership and the Mutex ensures safe concurrent access
     let network_client = Arc::new(network_client);
     
@@ -61,77 +114,296 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let openfaas_host = "http://localhost:8080".to_string();
     let openfaas_client = Arc::new(OpenFaasClient::new(openfaas_host, opt.docker_username));
     
+    let mut functions_service = FunctionsService::new(
+        Arc::clone(&network_client),
+        Arc::clone(&openfaas_client),
+        peer_id,
+        opt.cacheable_functions.into_iter().collect(),
+        inbound_policy,
+        InboundLimits {
+            max_concurrent: opt.max_concurrent_inbound,
+            max_queued: opt.max_queued_inbound,
+        },
+        PackageStore::open(&opt.package_dir)?,
+    );
+    functions_service = functions_service.with_labels(opt.labels.iter().cloned().collect());
+    if opt.accept_remote_deploy {
+        functions_service = functions_service.with_remote_deploy();
+    }
+    if opt.autoscale {
+        functions_service = functions_service.with_autoscaling(AutoscaleConfig {
+            interval: Duration::from_secs(opt.autoscale_interval_secs),
+            scale_up_invocations: opt.scale_up_invocations,
+            scale_up_in_progress: opt.scale_up_in_progress,
+            scale_down_idle: Duration::from_secs(opt.scale_down_idle_secs),
+        });
+    }
+    let functions_service = Arc::new(functions_service);
+
     spawn({
-    let network_client = Arc::clone(&network_client);
-    let openfaas_client = Arc::clone(&openfaas_client);
+    let functions_service = Arc::clone(&functions_service);
     async move {
         loop {
             match network_events.next().await {
                 // Reply with the content of the file on incoming requests.
-                Some(network::Event::InboundRequest { request, method, body, channel }) => {
+                Some(network::Event::InboundRequest { peer, request, channel }) => {
+                    spawn({
+                        let functions_service = Arc::clone(&functions_service);
+                        async move {
+                            functions_service.handle_inbound_request(peer, request, channel).await;
+                        }
+                    });
+                }
+                Some(network::Event::InboundCancel { peer, request_id }) => {
+                    functions_service.cancel_inbound_request(peer, request_id);
+                }
+                Some(network::Event::DeployRequested { peer, request, channel }) => {
+                    spawn({
+                        let functions_service = Arc::clone(&functions_service);
+                        async move {
+                            functions_service.handle_deploy_request(peer, request, channel).await;
+                        }
+                    });
+                }
+                Some(network::Event::ReplicaRequested { peer, request, channel }) => {
                     spawn({
-                        let network_client = Arc::clone(&network_client);
-                        let openfaas_client = Arc::clone(&openfaas_client);
+                        let functions_service = Arc::clone(&functions_service);
                         async move {
-                        // Http request to localhost:8000/functions/name
-                        let resp = openfaas_client.request_function(&request, &method, body).await;
-                        info!("Response received in main loop");
-                        let resp_status;
-                        let resp_body;
-                        match resp {
-                            Ok(resp) => {
-                                resp_status = resp.status().as_u16();
-                                resp_body = resp.bytes().await.unwrap().to_vec();
-                            }
-                            Err(err) => {
-                                error!("Failed to send request: {:?}", err);
-                                resp_status = 500;
-                                resp_body = "Failed to send request".as_bytes().to_vec(); 
-                            }
+                            functions_service.handle_replica_request(peer, request, channel).await;
                         }
-                        if let Err(err) = network_client.respond_function(resp_status, resp_body, channel).await {
-                            error!("Failed to respond with request result: {:?}", err);
+                    });
+                }
+                Some(network::Event::PackageRequested { peer, request, channel }) => {
+                    spawn({
+                        let functions_service = Arc::clone(&functions_service);
+                        async move {
+                            functions_service.handle_package_request(peer, request, channel).await;
                         }
-                    }
                     });
                 }
-                e => todo!("{:?}", e),
+                None => {
+                    info!("Network event stream closed");
+                    break;
+                }
             }
         }
     }
     });
     
-    let requests_in_progress = Arc::new(Mutex::new(RequestsInProgress::new()));
-    let functions_service = FunctionsService::new(
-        Arc::clone(&network_client),
-        Arc::clone(&openfaas_client),
-        Arc::clone(&requests_in_progress),
-        peer_id.clone(),
-    );
+    // Publish the load of this node for the other peers to avoid it when saturated.
+    spawn(Arc::clone(&functions_service).run_load_reports(Duration::from_secs(opt.load_report_interval_secs)));
+
+    // Restore the providers missing to the functions with a replication factor.
+    spawn(Arc::clone(&functions_service).run_replication_check(Duration::from_secs(opt.replication_check_interval_secs)));
+
+    // Replicate the hot functions on less loaded peers, when autoscaling is enabled.
+    spawn(Arc::clone(&functions_service).run_autoscaler());
+
+    // Serve the function streams opened by other peers.
+    let mut function_streams = network_client.accept_function_streams()?;
+    spawn({
+        let functions_service = Arc::clone(&functions_service);
+        async move {
+            while let Some((peer, stream)) = function_streams.next().await {
+                let functions_service = Arc::clone(&functions_service);
+                spawn(async move {
+                    functions_service.handle_inbound_stream(peer, stream).await;
+                });
+            }
+        }
+    });
+
     let app_state = http_server::server::AppState::new(
-        Arc::new(functions_service),
+        Arc::clone(&functions_service),
         );
     
-    http_server::server::run_http_server(app_state, opt.http_listen_port).await.expect("HTTP server failed.");
+    let server = http_server::server::run_http_server(app_state, opt.http_listen_port)?;
+
+    let sigterm = signal(SignalKind::terminate())?;
+    spawn(shutdown(
+        sigterm,
+        functions_service,
+        network_client,
+        server.handle(),
+        Duration::from_secs(opt.drain_timeout_secs),
+    ));
+
+    server.await?;
 
     Ok(())
 }
 
+/// Read the bootstrap peer multiaddrs from a file, one per line. Empty lines and lines starting with # are ignored.
+fn read_bootstrap_file(path: &Path) -> Result<Vec<Multiaddr>, FaasError> {
+    let content = fs::read_to_string(path)?;
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            line.parse::<Multiaddr>()
+                .map_err(|e| FaasError::BadInput(format!("Invalid bootstrap peer {} in {:?}: {}", line, path, e)))
+        })
+        .collect()
+}
+
+/// Read the pre-shared key of a private network, in the usual swarm.key format.
+fn read_swarm_key(path: &Path) -> Result<PreSharedKey, FaasError> {
+    let content = fs::read_to_string(path)?;
+    content
+        .parse::<PreSharedKey>()
+        .map_err(|e| FaasError::BadInput(format!("Invalid swarm key in {:?}: {}", path, e)))
+}
+
+/// Drain the node on SIGTERM, SIGINT or a drain request through the API,
+/// then close the swarm and stop the HTTP server.
+async fn shutdown(
+    mut sigterm: Signal,
+    functions_service: Arc<FunctionsService>,
+    network_client: Arc<network::NetworkClient>,
+    server_handle: ServerHandle,
+    drain_timeout: Duration,
+) {
+    tokio::select! {
+        _ = sigterm.recv() => info!("SIGTERM received, draining"),
+        _ = ctrl_c() => info!("SIGINT received, draining"),
+        _ = functions_service.drain_requested() => info!("Draining"),
+    }
+
+    functions_service.drain(drain_timeout).await;
+
+    if let Err(e) = network_client.shutdown().await {
+        error!("Failed to shut down network: {:?}", e);
+    }
+    server_handle.stop(true).await;
+    info!("Node shut down");
+}
+
 #[derive(Parser, Debug)]
 #[clap(name = "libp2p file sharing example")]
 struct Opt {
-    #[clap(long)]
-    p2p_listen_address: Multiaddr,
+    /// Address to listen on for P2P connections, e.g. /ip4/0.0.0.0/tcp/4001 or /ip4/0.0.0.0/udp/4001/quic-v1. Can be repeated.
+    #[clap(long = "p2p-listen-address", required = true)]
+    p2p_listen_addresses: Vec<Multiaddr>,
     /// Fixed value to generate deterministic peer ID.
     #[clap(long)]
     secret_key_seed: Option<u8>,
 
+    /// Multiaddr, including the peer ID, of a peer to join the network through. Can be repeated.
+    #[clap(long, visible_alias = "peer")]
+    bootstrap: Vec<Multiaddr>,
+
+    /// File with the multiaddrs of bootstrap peers, one per line.
+    #[clap(long)]
+    bootstrap_file: Option<PathBuf>,
+
+    /// Seconds between Kademlia bootstraps refreshing the routing table.
+    #[clap(long, default_value_t = 300, value_parser = clap::value_parser!(u64).range(1..))]
+    bootstrap_interval_secs: u64,
+
+    /// Discover peers in the local network with mDNS.
+    #[clap(long)]
+    mdns: bool,
+
+    /// Relay, with its peer ID, to listen through when the node is behind a NAT. Can be repeated.
+    #[clap(long)]
+    relay: Vec<Multiaddr>,
+
+    /// Act as a relay for nodes behind a NAT.
+    #[clap(long)]
+    relay_server: bool,
+
+    /// JSON file with the policy deciding which peers can invoke functions on this node. All peers are allowed by default.
+    #[clap(long)]
+    inbound_policy: Option<PathBuf>,
+
+    /// Directory where the packages of the deployed functions are kept for other nodes to replicate them.
+    #[clap(long, default_value = "packages")]
+    package_dir: PathBuf,
+
+    /// File with the pre-shared key of the private network to join. Only TCP is available in a private network.
     #[clap(long)]
-    peer: Option<Multiaddr>,
+    swarm_key: Option<PathBuf>,
 
     #[clap(long)]
     http_listen_port: u16,
 
     #[clap(long)]
     docker_username: String,
+
+    /// Function whose identical concurrent invocations share a single execution. Can be repeated.
+    #[clap(long = "cacheable-function")]
+    cacheable_functions: Vec<String>,
+
+    /// Seconds the providers found for a function are reused before querying the DHT again.
+    #[clap(long, default_value_t = 30)]
+    provider_cache_ttl_secs: u64,
+
+    /// Seconds between the load reports published to the other peers.
+    #[clap(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    load_report_interval_secs: u64,
+
+    /// Maximum number of established P2P connections.
+    #[clap(long, default_value_t = 256)]
+    max_connections: u32,
+
+    /// Maximum number of established P2P connections with a single peer.
+    #[clap(long, default_value_t = 4)]
+    max_connections_per_peer: u32,
+
+    /// Maximum number of invocations from other peers executed at the same time.
+    #[clap(long, default_value_t = 32)]
+    max_concurrent_inbound: usize,
+
+    /// Maximum number of invocations from other peers waiting to be executed. More are answered as busy.
+    #[clap(long, default_value_t = 128)]
+    max_queued_inbound: usize,
+
+    /// Seconds to wait for the response of a provider to a function request.
+    #[clap(long, default_value_t = 30)]
+    request_timeout_secs: u64,
+
+    /// Compress the messages of function requests with zstd or gzip, when the peer supports it.
+    #[clap(long)]
+    compression: Option<Compression>,
+
+    /// Minimum size in bytes of the function request messages to compress.
+    #[clap(long, default_value_t = 1024)]
+    compression_threshold: usize,
+
+    /// Label of the node, as key=value, to target deployments on it. Can be repeated.
+    #[clap(long = "label", value_parser = parse_label)]
+    labels: Vec<(String, String)>,
+
+    /// Deploy the functions sent by other peers, if the inbound policy allows them.
+    #[clap(long)]
+    accept_remote_deploy: bool,
+
+    /// Seconds between two checks of the providers of the functions with a replication factor.
+    #[clap(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    replication_check_interval_secs: u64,
+
+    /// Replicate the hot functions provided by this node on less loaded peers, and deploy the replicas other peers ask for.
+    #[clap(long)]
+    autoscale: bool,
+
+    /// Seconds between two samples of the demand for the functions.
+    #[clap(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    autoscale_interval_secs: u64,
+
+    /// Invocations of a function between two samples from which it gets a new replica.
+    #[clap(long, default_value_t = 100)]
+    scale_up_invocations: u64,
+
+    /// Invocations of a function in progress from which it gets a new replica.
+    #[clap(long, default_value_t = 16)]
+    scale_up_in_progress: usize,
+
+    /// Seconds without invocations after which a replica deployed for another peer is removed.
+    #[clap(long, default_value_t = 300)]
+    scale_down_idle_secs: u64,
+
+    /// Seconds to wait for requests in progress to finish when draining before shutting down.
+    #[clap(long, default_value_t = 30)]
+    drain_timeout_secs: u64,
 }
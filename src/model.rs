@@ -1,12 +1,93 @@
 use serde_json::Value;
 use serde_json::json;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
+#[derive(Debug, Clone)]
 pub(crate) struct OpenFaaSResponse {
     pub status: u16,
     pub body: Vec<u8>
 }
 
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ProviderCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+/// Load of a node, published periodically to the other peers.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct LoadReport {
+    /// Load average divided by the number of CPUs.
+    pub cpu: f32,
+    /// Fraction of the memory in use.
+    pub memory: f32,
+    /// Invocations from other peers being executed.
+    pub in_flight: u64,
+    /// Invocations from other peers waiting for a free execution slot.
+    pub queue_len: u64,
+    /// Functions deployed on the node.
+    pub warm_functions: Vec<String>,
+    /// Labels given by the operator to the node, e.g. its zone.
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// Invocations from other peers the node can still start at once.
+    #[serde(default)]
+    pub free_slots: u64,
+    /// Memory available, in MiB.
+    #[serde(default)]
+    pub free_memory_mb: u64,
+    /// The node is draining and rejects new invocations.
+    #[serde(default)]
+    pub draining: bool,
+}
+
+impl LoadReport {
+    /// Limit of the CPU or memory usage above which the node is considered saturated.
+    const SATURATION: f32 = 0.9;
+
+    /// Whether the node is running out of CPU or memory, already makes invocations wait for a
+    /// free execution slot or is draining, so that new invocations would wait or be rejected.
+    pub fn is_saturated(&self) -> bool {
+        self.draining
+            || self.cpu >= Self::SATURATION
+            || self.memory >= Self::SATURATION
+            || (self.free_slots == 0 && self.queue_len > 0)
+    }
+
+    /// Load used to compare nodes, the invocations in progress and then the CPU usage.
+    pub fn load(&self) -> (u64, f32) {
+        (self.in_flight + self.queue_len, self.cpu)
+    }
+}
+
+/// Parse a label written as `key=value`.
+pub(crate) fn parse_label(label: &str) -> Result<(String, String), String> {
+    match label.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("Invalid label {}, expected key=value", label)),
+    }
+}
+
+/// Bytes saved by compressing the messages exchanged with other peers.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct CompressionStats {
+    pub messages_compressed: u64,
+    pub bytes_saved_sent: u64,
+    pub bytes_saved_received: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct NodeMetrics {
+    pub provider_cache: ProviderCacheStats,
+    /// Smoothed round-trip time to each connected peer, in milliseconds.
+    pub peer_rtt_ms: HashMap<String, u64>,
+    /// Latest load reported by each peer.
+    pub peer_loads: HashMap<String, LoadReport>,
+    pub compression: CompressionStats,
+}
+
 #[derive(Clone, Serialize)]
 pub (crate) enum BodyData {
     Integer(i32),
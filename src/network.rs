@@ -15,28 +15,84 @@ use futures::prelude::*;
 use futures::StreamExt;
 
 use libp2p::{
+    autonat,
+    connection_limits::{self, ConnectionLimits},
     core::Multiaddr,
+    core::transport::ListenerId,
+    core::{upgrade, Transport},
+    dcutr,
+    gossipsub::{self, IdentTopic, MessageAuthenticity},
     identity, kad,
+    kad::store::RecordStore,
     multiaddr::Protocol,
     noise,
+    pnet::{PnetConfig, PreSharedKey},
     request_response::{self, OutboundRequestId, ProtocolSupport, ResponseChannel},
-    swarm::{NetworkBehaviour, Swarm, SwarmEvent},
-    tcp, yamux, PeerId,
-    identify::{Config as IdentifyConfig, Behaviour as IdentifyBehavior, Event as IdentifyEvent}
+    swarm::{behaviour::toggle::Toggle, dial_opts::DialOpts, DialError, NetworkBehaviour, Swarm, SwarmEvent},
+    mdns, ping, relay,
+    tcp, yamux, PeerId, StreamProtocol,
+    identify::{Config as IdentifyConfig, Behaviour as IdentifyBehavior, Event as IdentifyEvent},
 };
+use libp2p_stream::{IncomingStreams, OpenStreamError};
 
-use libp2p::StreamProtocol;
-use serde::{Deserialize, Serialize};
 use std::collections::{hash_map, HashMap, HashSet};
-use std::error::Error;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use tokio::time::timeout;
+use tokio::time::{interval, sleep, timeout};
 use tokio::sync::Mutex;
+use uuid::Uuid;
 use std::sync::Arc;
 
+use crate::data_structures::{PeerLatencies, PeerLoads, ProviderCache};
+use crate::error::FaasError;
+use crate::model::{CompressionStats, LoadReport, ProviderCacheStats};
+use crate::package::{
+    DeployRequest, DeployResponse, PackageRequest, PackageResponse, ReplicaReason, ReplicaRequest, ReplicaResponse,
+    DEPLOY_PROTOCOL, PACKAGE_PROTOCOL, REPLICA_PROTOCOL,
+};
+use crate::streaming::{self, BodyStream, STREAM_PROTOCOL};
+use crate::protocol::{
+    CancelRequest, CancelResponse, Compression, CompressionCounters, FunctionCodec, FunctionRequest, FunctionResponse,
+    CANCEL_PROTOCOL, PROTOCOL_V1, PROTOCOL_V2, PROTOCOL_V2_GZIP, PROTOCOL_V2_ZSTD,
+};
+
 use log::{info, error, warn};
 
+/// Configuration of the network layer.
+pub(crate) struct NetworkConfig {
+    /// Fixed value to generate a deterministic peer ID.
+    pub(crate) secret_key_seed: Option<u8>,
+    /// Time the providers found for a function are reused.
+    pub(crate) provider_cache_ttl: Duration,
+    /// Discover peers in the local network with mDNS.
+    pub(crate) enable_mdns: bool,
+    /// Interval between Kademlia bootstraps refreshing the routing table.
+    pub(crate) bootstrap_interval: Duration,
+    /// Relays, with their peer ID, to listen through when the node is not reachable from outside.
+    pub(crate) relays: Vec<Multiaddr>,
+    /// Act as a relay for other nodes.
+    pub(crate) enable_relay_server: bool,
+    /// Key of the private network to join, if any. Only nodes with the same key can connect.
+    pub(crate) pre_shared_key: Option<PreSharedKey>,
+    /// Time the load reported by a peer is taken into account.
+    pub(crate) load_report_ttl: Duration,
+    /// Maximum number of established connections, in total and per peer.
+    pub(crate) max_connections: u32,
+    pub(crate) max_connections_per_peer: u32,
+    /// Time to wait for the response to a function request.
+    pub(crate) request_timeout: Duration,
+    /// Compression preferred for the function requests, if any. Peers agree on it per connection.
+    pub(crate) compression: Option<Compression>,
+    /// Minimum size in bytes of the messages to compress.
+    pub(crate) compression_threshold: usize,
+}
+
+/// Gossipsub topic where the nodes publish their load.
+const LOAD_REPORT_TOPIC: &str = "load-reports";
+
+/// Time to wait for a peer to deploy a function, including the build of its image.
+const DEPLOY_TIMEOUT: Duration = Duration::from_secs(600);
+
 /// Creates the network components, namely:
 ///
 /// - The network client to interact with the network layer from anywhere
@@ -48,86 +104,210 @@ use log::{info, error, warn};
 /// 
 /// - Peer ID for the node.
 pub(crate) async fn new(
-    secret_key_seed: Option<u8>,
-) -> Result<(NetworkClient, impl Stream<Item = Event>, EventLoop, PeerId), Box<dyn Error>> {
+    config: NetworkConfig,
+) -> Result<(NetworkClient, impl Stream<Item = Event>, EventLoop, PeerId), FaasError> {
     // Create a public/private key pair, either random or based on a seed.
-    let id_keys = match secret_key_seed {
+    let id_keys = match config.secret_key_seed {
         Some(seed) => {
             let mut bytes = [0u8; 32];
             bytes[0] = seed;
-            identity::Keypair::ed25519_from_bytes(bytes).unwrap()
+            identity::Keypair::ed25519_from_bytes(bytes)
+                .map_err(|e| FaasError::BadInput(format!("Invalid secret key seed: {}", e)))?
         }
         None => identity::Keypair::generate_ed25519(),
     };
     let peer_id = id_keys.public().to_peer_id();
+    let compression_counters = Arc::new(CompressionCounters::default());
 
-    let mut swarm = libp2p::SwarmBuilder::with_existing_identity(id_keys)
-        .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
-        .with_behaviour(|key| Behaviour {
-            kademlia: kad::Behaviour::new(
-                peer_id,
-                kad::store::MemoryStore::new(key.public().to_peer_id()),
-            ),
-            request_response: request_response::cbor::Behaviour::new(
-                [(
-                    StreamProtocol::new("/function-request/1"),
-                    ProtocolSupport::Full,
-                )],
-                request_response::Config::default(),
-            ),
-            identify: IdentifyBehavior::new(
-                IdentifyConfig::new(
-                "/agent/connection/1.0.0".to_string(), 
-                key.clone().public()
-                )
+    let mut swarm = match &config.pre_shared_key {
+        // A private network only supports TCP, the pre-shared key protects the raw connections.
+        Some(psk) => {
+            let psk = *psk;
+            libp2p::SwarmBuilder::with_existing_identity(id_keys)
+                .with_tokio()
+                .with_other_transport(|key| {
+                    Ok(tcp::tokio::Transport::new(tcp::Config::default())
+                        .and_then(move |socket, _| PnetConfig::new(psk).handshake(socket))
+                        .upgrade(upgrade::Version::V1)
+                        .authenticate(noise::Config::new(key)?)
+                        .multiplex(yamux::Config::default()))
+                })
+                .map_err(|e| FaasError::Network(format!("Failed to set up private TCP transport: {}", e)))?
+                .with_dns()
+                .map_err(|e| FaasError::Network(format!("Failed to set up DNS resolution: {}", e)))?
+                .with_relay_client(noise::Config::new, yamux::Config::default)
+                .map_err(|e| FaasError::Network(format!("Failed to set up relay client: {}", e)))?
+                .with_behaviour(|key, relay_client| new_behaviour(key, relay_client, &config, &compression_counters))
+                .map_err(|e| FaasError::Network(format!("Failed to set up network behaviour: {}", e)))?
+                .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+                .build()
+        }
+        None => libp2p::SwarmBuilder::with_existing_identity(id_keys)
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
             )
-        })?
-        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
-        .build();
+            .map_err(|e| FaasError::Network(format!("Failed to set up TCP transport: {}", e)))?
+            .with_quic()
+            .with_dns()
+            .map_err(|e| FaasError::Network(format!("Failed to set up DNS resolution: {}", e)))?
+            .with_websocket(noise::Config::new, yamux::Config::default)
+            .await
+            .map_err(|e| FaasError::Network(format!("Failed to set up WebSocket transport: {}", e)))?
+            .with_relay_client(noise::Config::new, yamux::Config::default)
+            .map_err(|e| FaasError::Network(format!("Failed to set up relay client: {}", e)))?
+            .with_behaviour(|key, relay_client| new_behaviour(key, relay_client, &config, &compression_counters))
+            .map_err(|e| FaasError::Network(format!("Failed to set up network behaviour: {}", e)))?
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build(),
+    };
 
     swarm
         .behaviour_mut()
         .kademlia
         .set_mode(Some(kad::Mode::Server));
 
+    swarm
+        .behaviour_mut()
+        .gossipsub
+        .subscribe(&IdentTopic::new(LOAD_REPORT_TOPIC))
+        .map_err(|e| FaasError::Network(format!("Failed to subscribe to load reports: {}", e)))?;
+
+    // Relays are reachable from outside, so they can also probe whether this node is.
+    for relay in &config.relays {
+        if let Some(Protocol::P2p(relay_peer_id)) = relay.iter().last() {
+            swarm.behaviour_mut().autonat.add_server(relay_peer_id, Some(relay.clone()));
+        }
+    }
+
+    let latencies = Arc::new(Mutex::new(PeerLatencies::new()));
+    let loads = Arc::new(Mutex::new(PeerLoads::new(config.load_report_ttl)));
     let (command_sender, command_receiver) = mpsc::channel(0);
     let (event_sender, event_receiver) = mpsc::channel(0);
 
     Ok((
         NetworkClient {
             sender: Arc::new(Mutex::new(command_sender)),
+            provider_cache: Arc::new(Mutex::new(ProviderCache::new(config.provider_cache_ttl))),
+            latencies: Arc::clone(&latencies),
+            loads: Arc::clone(&loads),
+            stream_control: swarm.behaviour().stream.new_control(),
+            compression_counters,
         },
         event_receiver,
-        EventLoop::new(swarm, command_receiver, event_sender),
+        EventLoop::new(swarm, command_receiver, event_sender, config.bootstrap_interval, config.relays, latencies, loads),
         peer_id
     ))
 }
 
+/// Versions of the function request protocol, in order of preference. Without compression
+/// configured, the compressed variants are not offered so peers always send plain messages.
+fn function_protocols(compression: Option<Compression>) -> Vec<(StreamProtocol, ProtocolSupport)> {
+    let mut protocols = match compression {
+        Some(Compression::Zstd) => vec![PROTOCOL_V2_ZSTD, PROTOCOL_V2_GZIP],
+        Some(Compression::Gzip) => vec![PROTOCOL_V2_GZIP, PROTOCOL_V2_ZSTD],
+        None => Vec::new(),
+    };
+    protocols.extend([PROTOCOL_V2, PROTOCOL_V1]);
+    protocols.into_iter().map(|protocol| (protocol, ProtocolSupport::Full)).collect()
+}
+
+/// Creates the network behaviour, shared by the public and the private network transports.
+fn new_behaviour(
+    key: &identity::Keypair,
+    relay_client: relay::client::Behaviour,
+    config: &NetworkConfig,
+    compression_counters: &Arc<CompressionCounters>,
+) -> Result<Behaviour, Box<dyn std::error::Error + Send + Sync>> {
+    let peer_id = key.public().to_peer_id();
+    Ok(Behaviour {
+        kademlia: kad::Behaviour::new(
+            peer_id,
+            kad::store::MemoryStore::new(peer_id),
+        ),
+        // Protocols are negotiated in order, the first version is kept for nodes not upgraded yet.
+        request_response: request_response::Behaviour::with_codec(
+            FunctionCodec::new(config.compression_threshold, Arc::clone(compression_counters)),
+            function_protocols(config.compression),
+            request_response::Config::default().with_request_timeout(config.request_timeout),
+        ),
+        cancel: request_response::cbor::Behaviour::new(
+            [(CANCEL_PROTOCOL, ProtocolSupport::Full)],
+            request_response::Config::default(),
+        ),
+        package: request_response::cbor::Behaviour::new(
+            [(PACKAGE_PROTOCOL, ProtocolSupport::Full)],
+            request_response::Config::default().with_request_timeout(config.request_timeout),
+        ),
+        replica: request_response::cbor::Behaviour::new(
+            [(REPLICA_PROTOCOL, ProtocolSupport::Full)],
+            request_response::Config::default(),
+        ),
+        // Building the function image can take minutes.
+        deploy: request_response::cbor::Behaviour::new(
+            [(DEPLOY_PROTOCOL, ProtocolSupport::Full)],
+            request_response::Config::default().with_request_timeout(DEPLOY_TIMEOUT),
+        ),
+        identify: IdentifyBehavior::new(
+            IdentifyConfig::new(
+            "/agent/connection/1.0.0".to_string(), 
+            key.clone().public()
+            )
+        ),
+        mdns: if config.enable_mdns {
+            Toggle::from(Some(mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id)?))
+        } else {
+            Toggle::from(None)
+        },
+        relay_client,
+        relay_server: if config.enable_relay_server {
+            Toggle::from(Some(relay::Behaviour::new(peer_id, relay::Config::default())))
+        } else {
+            Toggle::from(None)
+        },
+        autonat: autonat::Behaviour::new(peer_id, autonat::Config::default()),
+        dcutr: dcutr::Behaviour::new(peer_id),
+        ping: ping::Behaviour::new(ping::Config::new()),
+        gossipsub: gossipsub::Behaviour::new(MessageAuthenticity::Signed(key.clone()), gossipsub::Config::default())?,
+        stream: libp2p_stream::Behaviour::new(),
+        connection_limits: connection_limits::Behaviour::new(
+            ConnectionLimits::default()
+                .with_max_established(Some(config.max_connections))
+                .with_max_established_per_peer(Some(config.max_connections_per_peer)),
+        ),
+    })
+}
+
 #[derive(Clone)]
 pub(crate) struct NetworkClient {
     sender: Arc<Mutex<mpsc::Sender<Command>>>,
+    provider_cache: Arc<Mutex<ProviderCache>>,
+    latencies: Arc<Mutex<PeerLatencies>>,
+    loads: Arc<Mutex<PeerLoads>>,
+    stream_control: libp2p_stream::Control,
+    compression_counters: Arc<CompressionCounters>,
 }
 
 impl NetworkClient {
+    /// Send a command to the network event loop.
+    async fn send_command(&self, command: Command) -> Result<(), FaasError> {
+        let mut locked_sender = self.sender.lock().await;
+        locked_sender
+            .send(command)
+            .await
+            .map_err(|_| FaasError::Network("Network event loop is not running".to_string()))
+    }
+
     /// Listen for incoming connections on the given address.
     pub(crate) async fn start_listening(
         &self,
         addr: Multiaddr,
-    ) -> Result<(), Box<dyn Error + Send>> {
+    ) -> Result<(), FaasError> {
         let (sender, receiver) = oneshot::channel();
-        let sender_clone = Arc::clone(&self.sender);
-        {
-        let mut locked_sender = sender_clone.lock().await;
-        locked_sender.send(Command::StartListening { addr, sender })
-            .await
-            .expect("Command receiver not to be dropped.");
-        }
-        receiver.await.expect("Sender not to be dropped.")
+        self.send_command(Command::StartListening { addr, sender }).await?;
+        receiver.await.map_err(|_| event_loop_stopped())?
     }
 
     /// Dial the given peer at the given address.
@@ -135,49 +315,158 @@ impl NetworkClient {
         &self,
         peer_id: PeerId,
         peer_addr: Multiaddr,
-    ) -> Result<(), Box<dyn Error + Send>> {
+    ) -> Result<(), FaasError> {
         let (sender, receiver) = oneshot::channel();
-        let sender_clone = Arc::clone(&self.sender);
-        {
-        let mut locked_sender = sender_clone.lock().await;
-        locked_sender.send(Command::Dial {
+        self.send_command(Command::Dial {
                 peer_id,
                 peer_addr,
                 sender,
             })
-            .await
-            .expect("Command receiver not to be dropped.");
+            .await?;
+        receiver.await.map_err(|_| event_loop_stopped())?
+    }
+
+    /// Dial the given peer, retrying with exponential backoff until it succeeds or the attempts run out.
+    pub(crate) async fn dial_with_backoff(
+        &self,
+        peer_id: PeerId,
+        peer_addr: Multiaddr,
+        max_attempts: u32,
+    ) -> Result<(), FaasError> {
+        let mut backoff = Duration::from_secs(1);
+        let mut attempt = 1;
+        loop {
+            match self.dial(peer_id, peer_addr.clone()).await {
+                Ok(()) => {
+                    info!("Connected to bootstrap peer {:?} at {:?}", peer_id, peer_addr);
+                    return Ok(());
+                }
+                Err(e) if attempt < max_attempts => {
+                    warn!("Dial attempt {} to {:?} failed, retrying in {:?}: {:?}", attempt, peer_addr, backoff, e);
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(Duration::from_secs(60));
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
         }
-        receiver.await.expect("Sender not to be dropped.")
     }
 
     /// Advertise the local node as the provider of the given function on the DHT.
-    pub(crate) async fn start_providing(&self, function_name: String) {
-        let providers = self.get_providers(function_name.clone()).await;
+    pub(crate) async fn start_providing(&self, function_name: String) -> Result<(), FaasError> {
+        let providers = self.lookup_providers(function_name.clone()).await;
         info!("Providers before start providing: {:?}", providers);
         let (sender, receiver) = oneshot::channel();
-        let sender_clone = Arc::clone(&self.sender);
-        {
-        let mut locked_sender = sender_clone.lock().await;
-        locked_sender
-            .send(Command::StartProviding { function_name: function_name.clone(), sender })
-            .await
-            .expect("Command receiver not to be dropped.");
-        }
-        receiver.await.expect("Sender not to be dropped.");
-        let providers = self.get_providers(function_name.clone()).await;
+        self.send_command(Command::StartProviding { function_name: function_name.clone(), sender }).await?;
+        receiver.await.map_err(|_| event_loop_stopped())??;
+        let providers = self.lookup_providers(function_name.clone()).await;
         info!("Providers after start providing: {:?}", providers);
+        self.provider_cache.lock().await.invalidate(&function_name);
+        Ok(())
     }
 
-    /// Find the providers for the given function on the DHT.
+    /// Withdraw the provider record of the function.
+    pub(crate) async fn stop_providing(&self, function_name: String) -> Result<(), FaasError> {
+        self.send_command(Command::StopProviding { function_name: function_name.clone() }).await?;
+        self.provider_cache.lock().await.invalidate(&function_name);
+        Ok(())
+    }
+
+    /// Withdraw the provider records of every function provided by the local node.
+    pub(crate) async fn stop_providing_all(&self) -> Result<Vec<String>, FaasError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send_command(Command::StopProvidingAll { sender }).await?;
+        let function_names = receiver.await.map_err(|_| event_loop_stopped())?;
+        let mut provider_cache = self.provider_cache.lock().await;
+        for function_name in &function_names {
+            provider_cache.invalidate(function_name);
+        }
+        Ok(function_names)
+    }
+
+    /// Stop the network event loop, closing the swarm.
+    pub(crate) async fn shutdown(&self) -> Result<(), FaasError> {
+        self.send_command(Command::Shutdown).await
+    }
+
+    /// Find the providers for the given function, from the provider cache if still valid or on the DHT otherwise.
     pub(crate) async fn get_providers(&self, function_name: String) -> HashSet<PeerId> {
+        if let Some(providers) = self.provider_cache.lock().await.get(&function_name) {
+            return providers;
+        }
+        let providers = self.lookup_providers(function_name.clone()).await;
+        self.provider_cache.lock().await.insert(function_name, providers.clone());
+        providers
+    }
+
+    /// Forget the cached providers of the given function, e.g. after a request to them failed.
+    pub(crate) async fn invalidate_providers(&self, function_name: &str) {
+        self.provider_cache.lock().await.invalidate(function_name);
+    }
+
+    /// Forget a single cached provider of the given function.
+    pub(crate) async fn invalidate_provider(&self, function_name: &str, provider: &PeerId) {
+        self.provider_cache.lock().await.remove_provider(function_name, provider);
+    }
+
+    /// Time the peers cache the providers of a function before looking them up again.
+    pub(crate) async fn provider_cache_ttl(&self) -> Duration {
+        self.provider_cache.lock().await.ttl()
+    }
+
+    pub(crate) async fn provider_cache_stats(&self) -> ProviderCacheStats {
+        self.provider_cache.lock().await.stats()
+    }
+
+    pub(crate) fn compression_stats(&self) -> CompressionStats {
+        self.compression_counters.stats()
+    }
+
+    /// Current round-trip time estimates to the connected peers.
+    pub(crate) async fn peer_latencies(&self) -> PeerLatencies {
+        self.latencies.lock().await.clone()
+    }
+
+    /// Latest load reported by the peers.
+    pub(crate) async fn peer_loads(&self) -> PeerLoads {
+        self.loads.lock().await.clone()
+    }
+
+    /// Functions this node provides.
+    pub(crate) async fn provided_functions(&self) -> Result<Vec<String>, FaasError> {
         let (sender, receiver) = oneshot::channel();
-        let sender_clone = Arc::clone(&self.sender);
-        {
-        let mut locked_sender = sender_clone.lock().await;
-        locked_sender.send(Command::GetProviders { function_name, sender })
-            .await
-            .expect("Command receiver not to be dropped.");
+        self.send_command(Command::ProvidedFunctions { sender }).await?;
+        receiver.await.map_err(|_| event_loop_stopped())
+    }
+
+    /// Publish the load of this node to the other peers.
+    pub(crate) async fn publish_load_report(&self, report: LoadReport) -> Result<(), FaasError> {
+        self.send_command(Command::PublishLoadReport { report }).await
+    }
+
+    /// Refresh in the background the cached providers in use before they expire,
+    /// so that requests for them do not wait for a DHT query.
+    pub(crate) async fn run_provider_cache_refresh(self) {
+        let ttl = self.provider_cache.lock().await.ttl();
+        // Entries are refreshed between half and three quarters of their time to live.
+        let mut refresh_interval = interval((ttl / 4).max(Duration::from_secs(1)));
+        loop {
+            refresh_interval.tick().await;
+            let function_names = self.provider_cache.lock().await.due_for_refresh();
+            for function_name in function_names {
+                let providers = self.lookup_providers(function_name.clone()).await;
+                info!("Refreshed cached providers of {:?}: {:?}", function_name, providers);
+                self.provider_cache.lock().await.insert(function_name, providers);
+            }
+        }
+    }
+
+    /// Find the providers for the given function on the DHT.
+    async fn lookup_providers(&self, function_name: String) -> HashSet<PeerId> {
+        let (sender, receiver) = oneshot::channel();
+        if let Err(e) = self.send_command(Command::GetProviders { function_name, sender }).await {
+            error!("Failed to look up providers: {:?}", e);
+            return HashSet::new();
         }
         
         // Add 5 sec timeout to avoid infinite waiting
@@ -188,72 +477,238 @@ impl NetworkClient {
         }
     }
 
-    /// Request the content of the given function from the given peer.
+    /// Request the content of the given function from the given peer, which can give up
+    /// after the given timeout. The request is cancelled on the peer if the caller stops waiting.
     pub(crate) async fn request_function(
         &self,
         peer: PeerId,
         function_name: String,
         method: String,
         body: Option<Vec<u8>>,
-    ) -> Result<FunctionResponse, Box<dyn Error + Send>> {
+        timeout: Option<Duration>,
+    ) -> Result<FunctionResponse, FaasError> {
         let (sender, receiver) = oneshot::channel();
-        let sender_clone = Arc::clone(&self.sender);
-        info!("Sending request function command");
-        {
-        let mut locked_sender = sender_clone.lock().await;
-        locked_sender.send(Command::RequestFunction {
-                function_name,
-                method,
-                body,
+        let request_id = Uuid::new_v4().to_string();
+        let request = FunctionRequest {
+            function: function_name,
+            method,
+            body,
+            request_id: Some(request_id.clone()),
+            headers: HashMap::new(),
+            timeout_ms: timeout.map(|timeout| timeout.as_millis() as u64),
+        };
+        info!("Sending request function command, request id: {:?}", request_id);
+        self.send_command(Command::RequestFunction {
+                request,
                 peer,
                 sender,
             })
-            .await
-            .expect("Command receiver not to be dropped.");
-        }
+            .await?;
         info!("Waiting for response");
-        
-        let res = receiver.await.expect("Sender not be dropped.");
+
+        let mut cancel_guard = CancelOnDrop { client: self.clone(), peer, request_id: Some(request_id) };
+        let res = receiver.await.map_err(|_| event_loop_stopped());
+        cancel_guard.request_id = None;
+        let res = res?;
         info!("Response received");
         info!("Response: {:?}", res);
         res
     }
 
+    /// Request the given function from the given peer, streaming the request and response bodies.
+    pub(crate) async fn request_function_stream(
+        &self,
+        peer: PeerId,
+        function_name: String,
+        method: String,
+        body: BodyStream,
+        timeout: Option<Duration>,
+    ) -> Result<(FunctionResponse, BodyStream), FaasError> {
+        let request = FunctionRequest {
+            function: function_name,
+            method,
+            body: None,
+            request_id: Some(Uuid::new_v4().to_string()),
+            headers: HashMap::new(),
+            timeout_ms: timeout.map(|timeout| timeout.as_millis() as u64),
+        };
+        info!("Opening function stream to peer {:?}, request id: {:?}", peer, request.request_id);
+        let stream = self.open_function_stream(peer).await?;
+        streaming::send_request(stream, request, body).await
+    }
+
+    async fn open_function_stream(&self, peer: PeerId) -> Result<libp2p::Stream, FaasError> {
+        match self.stream_control.clone().open_stream(peer, STREAM_PROTOCOL).await {
+            Ok(stream) => Ok(stream),
+            Err(OpenStreamError::UnsupportedProtocol(_)) => Err(FaasError::ProviderFailure(format!("Provider {} does not support streaming", peer))),
+            Err(e) => Err(FaasError::Network(format!("Failed to open stream to peer {}: {}", peer, e))),
+        }
+    }
+
+    /// Function streams opened by other peers.
+    pub(crate) fn accept_function_streams(&self) -> Result<IncomingStreams, FaasError> {
+        self.stream_control
+            .clone()
+            .accept(STREAM_PROTOCOL)
+            .map_err(|_| FaasError::Internal("Function streams are already accepted".to_string()))
+    }
+
+    /// Ask the peer to stop executing the given request.
+    pub(crate) async fn cancel_function(&self, peer: PeerId, request_id: String) -> Result<(), FaasError> {
+        info!("Cancelling request {:?} on peer {:?}", request_id, peer);
+        self.send_command(Command::CancelFunction { peer, request_id }).await
+    }
+
+    /// Fetch the deployment package of the function from the given peer, the one with the
+    /// given digest if any or its latest one otherwise.
+    pub(crate) async fn request_package(&self, peer: PeerId, function_name: String, digest: Option<String>) -> Result<PackageResponse, FaasError> {
+        let (sender, receiver) = oneshot::channel();
+        info!("Requesting package {:?} of function {:?} from peer {:?}", digest, function_name, peer);
+        self.send_command(Command::RequestPackage {
+                request: PackageRequest { function: function_name, digest },
+                peer,
+                sender,
+            })
+            .await?;
+        receiver.await.map_err(|_| event_loop_stopped())?
+    }
+
+    /// Deploy a function on the given peer, waiting until it is deployed.
+    pub(crate) async fn request_deploy(&self, peer: PeerId, request: DeployRequest) -> Result<DeployResponse, FaasError> {
+        let (sender, receiver) = oneshot::channel();
+        info!("Deploying function {:?} on peer {:?}", request.function, peer);
+        self.send_command(Command::RequestDeploy { request, peer, sender }).await?;
+        receiver.await.map_err(|_| event_loop_stopped())?
+    }
+
+    /// Tell the peer the result of the deployment it asked for.
+    pub(crate) async fn respond_deploy(
+        &self,
+        response: DeployResponse,
+        channel: ResponseChannel<DeployResponse>,
+    ) -> Result<(), FaasError> {
+        self.send_command(Command::RespondDeploy { response, channel }).await
+    }
+
+    /// Ask the given peer to deploy a replica of the function.
+    pub(crate) async fn request_replica(&self, peer: PeerId, function_name: String, reason: ReplicaReason, digest: Option<String>) -> Result<ReplicaResponse, FaasError> {
+        let (sender, receiver) = oneshot::channel();
+        info!("Asking peer {:?} to replicate function {:?}", peer, function_name);
+        self.send_command(Command::RequestReplica {
+                request: ReplicaRequest { function: function_name, reason, digest },
+                peer,
+                sender,
+            })
+            .await?;
+        receiver.await.map_err(|_| event_loop_stopped())?
+    }
+
+    /// Tell the peer whether a replica of the function it asked for is being deployed.
+    pub(crate) async fn respond_replica(
+        &self,
+        response: ReplicaResponse,
+        channel: ResponseChannel<ReplicaResponse>,
+    ) -> Result<(), FaasError> {
+        self.send_command(Command::RespondReplica { response, channel }).await
+    }
+
+    /// Respond with the package of a function to the given request.
+    pub(crate) async fn respond_package(
+        &self,
+        response: PackageResponse,
+        channel: ResponseChannel<PackageResponse>,
+    ) -> Result<(), FaasError> {
+        self.send_command(Command::RespondPackage { response, channel }).await
+    }
+
     /// Respond with the provided function content to the given request.
     pub(crate) async fn respond_function(
         &self,
-        function_response_status: u16,
-        function_response_body: Vec<u8>,
+        response: FunctionResponse,
         channel: ResponseChannel<FunctionResponse>,
-    ) -> Result<(), Box<dyn Error + Send>> {
-        let sender_clone = Arc::clone(&self.sender);
-        {
-        let mut locked_sender = sender_clone.lock().await;
-        info!("Sender locked. Sending response");
-        info!("Response status: {:?}", function_response_status);
-        info!("Response body: {:?}", function_response_body);
-
-        let r = locked_sender
-            .send(Command::RespondFunction { function_response_status, function_response_body, channel })
-            .await;
-        match r {
-            Ok(_) => info!("Response sent"),
-            Err(e) => info!("Error sending response: {:?}", e),
-        }
-        }
+    ) -> Result<(), FaasError> {
+        info!("Sending response");
+        info!("Response status: {:?}", response.status);
+        info!("Response body: {:?}", response.body);
+
+        self.send_command(Command::RespondFunction { response, channel }).await?;
         info!("Response sent");
         Ok(())
     }
 }
 
+/// Cancels a function request on the provider when the caller stops waiting for its response,
+/// e.g. because its deadline passed or another provider answered first.
+struct CancelOnDrop {
+    client: NetworkClient,
+    peer: PeerId,
+    request_id: Option<String>,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        let Some(request_id) = self.request_id.take() else {
+            return;
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let client = self.client.clone();
+        let peer = self.peer;
+        runtime.spawn(async move {
+            if let Err(e) = client.cancel_function(peer, request_id).await {
+                warn!("Failed to cancel request on peer {:?}: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+/// Whether the address uses the QUIC transport.
+pub(crate) fn is_quic(addr: &Multiaddr) -> bool {
+    addr.iter().any(|protocol| matches!(protocol, Protocol::QuicV1))
+}
+
+/// Order the addresses with the QUIC ones first, keeping the relative order otherwise.
+fn prefer_quic(mut addrs: Vec<Multiaddr>) -> Vec<Multiaddr> {
+    addrs.sort_by_key(|addr| !is_quic(addr));
+    addrs
+}
+
+fn event_loop_stopped() -> FaasError {
+    FaasError::Network("Network event loop stopped before answering".to_string())
+}
+
+type PendingSender<T> = oneshot::Sender<Result<T, FaasError>>;
+
+/// Providers found for a lookup, waiting for the dials to the ones not connected yet.
+struct PendingProviderDials {
+    providers: HashSet<PeerId>,
+    dialing: HashSet<PeerId>,
+    deadline: Instant,
+    sender: oneshot::Sender<HashSet<PeerId>>,
+}
+
 pub(crate) struct EventLoop {
     swarm: Swarm<Behaviour>,
     command_receiver: mpsc::Receiver<Command>,
-    event_sender: Arc<Mutex<mpsc::Sender<Event>>>,
-    pending_dial: Arc<Mutex<HashMap<PeerId, oneshot::Sender<Result<(), Box<dyn Error + Send>>>>>>,
-    pending_start_providing: Arc<Mutex<HashMap<kad::QueryId, oneshot::Sender<()>>>>,
-    pending_get_providers: Arc<Mutex<HashMap<kad::QueryId, oneshot::Sender<HashSet<PeerId>>>>>,
-    pending_request_function: Arc<Mutex<HashMap<OutboundRequestId, oneshot::Sender<Result<FunctionResponse, Box<dyn Error + Send>>>>>>,
+    event_sender: mpsc::Sender<Event>,
+    pending_dial: HashMap<PeerId, PendingSender<()>>,
+    pending_start_providing: HashMap<kad::QueryId, PendingSender<()>>,
+    pending_get_providers: HashMap<kad::QueryId, oneshot::Sender<HashSet<PeerId>>>,
+    pending_provider_dials: Vec<PendingProviderDials>,
+    pending_request_function: HashMap<OutboundRequestId, PendingSender<FunctionResponse>>,
+    pending_request_package: HashMap<OutboundRequestId, PendingSender<PackageResponse>>,
+    pending_request_replica: HashMap<OutboundRequestId, PendingSender<ReplicaResponse>>,
+    pending_request_deploy: HashMap<OutboundRequestId, PendingSender<DeployResponse>>,
+    // Addresses peers listen on, as reported by identify and mDNS
+    peer_addrs: HashMap<PeerId, Vec<Multiaddr>>,
+    // Peers dialed over QUIC only, dialed again over their other addresses if that fails
+    quic_dials: HashSet<PeerId>,
+    bootstrap_interval: Duration,
+    relays: Vec<Multiaddr>,
+    relay_listeners: Vec<ListenerId>,
+    latencies: Arc<Mutex<PeerLatencies>>,
+    loads: Arc<Mutex<PeerLoads>>,
 }
 
 impl EventLoop {
@@ -261,23 +716,46 @@ impl EventLoop {
         swarm: Swarm<Behaviour>,
         command_receiver: mpsc::Receiver<Command>,
         event_sender: mpsc::Sender<Event>,
+        bootstrap_interval: Duration,
+        relays: Vec<Multiaddr>,
+        latencies: Arc<Mutex<PeerLatencies>>,
+        loads: Arc<Mutex<PeerLoads>>,
     ) -> Self {
         Self {
-            swarm: swarm,
-            command_receiver: command_receiver,
-            event_sender: Arc::new(Mutex::new(event_sender)),
-            pending_dial: Arc::new(Mutex::new(Default::default())),
-            pending_start_providing: Arc::new(Mutex::new(Default::default())),
-            pending_get_providers: Arc::new(Mutex::new(Default::default())),
-            pending_request_function: Arc::new(Mutex::new(Default::default())),
+            swarm,
+            command_receiver,
+            event_sender,
+            pending_dial: Default::default(),
+            pending_start_providing: Default::default(),
+            pending_get_providers: Default::default(),
+            pending_provider_dials: Default::default(),
+            pending_request_function: Default::default(),
+            pending_request_package: Default::default(),
+            pending_request_replica: Default::default(),
+            pending_request_deploy: Default::default(),
+            peer_addrs: Default::default(),
+            quic_dials: Default::default(),
+            bootstrap_interval,
+            relays,
+            relay_listeners: Default::default(),
+            latencies,
+            loads,
         }
     }
 
     pub(crate) async fn run(mut self) {
+        let mut dial_deadline_interval = interval(Duration::from_millis(500));
+        let mut bootstrap_interval = interval(self.bootstrap_interval);
         loop {
             tokio::select! {
                 event = self.swarm.select_next_some() => self.handle_event(event).await,
+                _ = dial_deadline_interval.tick() => self.expire_provider_dials(),
+                _ = bootstrap_interval.tick() => self.bootstrap(),
                 command = self.command_receiver.next() => match command {
+                    Some(Command::Shutdown) => {
+                        info!("Shutting down network event loop");
+                        return;
+                    }
                     Some(c) => {
                         info!("Received command: {:?}", c);
                         self.handle_command(c).await;
@@ -289,11 +767,6 @@ impl EventLoop {
     }
 
     async fn handle_event(&mut self, event: SwarmEvent<BehaviourEvent>) {
-        let mut event_sender = self.event_sender.lock().await;
-        let mut pending_dial = self.pending_dial.lock().await;
-        let mut pending_start_providing = self.pending_start_providing.lock().await;
-        let mut pending_get_providers = self.pending_get_providers.lock().await;
-        let mut pending_request_function = self.pending_request_function.lock().await;
         match event {
             SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
                 kad::Event::OutboundQueryProgressed {
@@ -302,10 +775,9 @@ impl EventLoop {
                     ..
                 },
             )) => {
-                let sender: oneshot::Sender<()> = pending_start_providing
-                    .remove(&id)
-                    .expect("Completed query to be previously pending.");
-                let _ = sender.send(());
+                if let Some(sender) = self.pending_start_providing.remove(&id) {
+                    let _ = sender.send(Ok(()));
+                }
             }
             SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
                 kad::Event::OutboundQueryProgressed {
@@ -318,72 +790,87 @@ impl EventLoop {
                     ..
                 },
             )) => {
-                if let Some(sender) = pending_get_providers.remove(&id) {
-                    
-                    for provider in &providers {
-                        // TODO check if we are already connected to the provider
-                        if !self.swarm.is_connected(provider) {
-                            match self.swarm.dial(provider.clone()) {
-                                Ok(()) => {
-                                    let (dial_sender, dial_receiver) = oneshot::channel();
-                                    pending_dial.insert(provider.clone(), dial_sender);
-                                    info!("Dialing provider {:?}", provider);
-                                    // Add 3 sec timeout to avoid infinite waiting, get providers timeout has to be higher than this timeout
-                                    let _ = timeout(Duration::from_secs(3), dial_receiver).await;
-                                }
-                                Err(e) => {
-                                    error!("Error dialing provider {:?}: {:?}", provider, e);
-                                }
-                            }                
-                        }
-                    }
-
-                    sender.send(providers).expect("Receiver not to be dropped");
+                if let Some(sender) = self.pending_get_providers.remove(&id) {
+                    self.dial_providers(providers, sender);
 
                     // Finish the query. We are only interested in the first result.
-                    self.swarm
-                        .behaviour_mut()
-                        .kademlia
-                        .query_mut(&id)
-                        .unwrap()
-                        .finish();
+                    if let Some(mut query) = self.swarm.behaviour_mut().kademlia.query_mut(&id) {
+                        query.finish();
+                    }
                 }
             }
             SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
                 kad::Event::OutboundQueryProgressed {
+                    id,
                     result:
                         kad::QueryResult::GetProviders(Ok(
                             kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. },
                         )),
                     ..
                 },
-            )) => {}
+            )) => {
+                // The query ended without finding any provider.
+                if let Some(sender) = self.pending_get_providers.remove(&id) {
+                    let _ = sender.send(HashSet::new());
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
+                kad::Event::OutboundQueryProgressed {
+                    id,
+                    result: kad::QueryResult::GetProviders(Err(e)),
+                    ..
+                },
+            )) => {
+                warn!("Providers lookup failed: {:?}", e);
+                if let Some(sender) = self.pending_get_providers.remove(&id) {
+                    let _ = sender.send(HashSet::new());
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(
+                kad::Event::OutboundQueryProgressed {
+                    result: kad::QueryResult::Bootstrap(result),
+                    step,
+                    ..
+                },
+            )) => match result {
+                Ok(kad::BootstrapOk { peer, num_remaining }) => {
+                    if step.last {
+                        info!("Kademlia bootstrap finished, last peer: {:?}", peer);
+                    } else {
+                        info!("Kademlia bootstrap progressed, peer: {:?}, remaining: {:?}", peer, num_remaining);
+                    }
+                }
+                Err(e) => warn!("Kademlia bootstrap failed: {:?}", e),
+            },
             SwarmEvent::Behaviour(BehaviourEvent::Kademlia(_)) => {}
             SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
-                request_response::Event::Message { message, .. },
+                request_response::Event::Message { peer, message },
             )) => match message {
                 request_response::Message::Request {
                     request, channel, ..
                 } => {
                     info!("Sending inbound request event: {:?}", request);
-                    event_sender
+                    let sent = self.event_sender
                         .send(Event::InboundRequest {
-                            request: request.0,
-                            method: request.1,
-                            body: request.2,
+                            peer,
+                            request,
                             channel,
                         })
-                        .await
-                        .expect("Event receiver not to be dropped.");
+                        .await;
+                    if let Err(e) = sent {
+                        error!("Failed to deliver inbound request: {:?}", e);
+                    }
                 }
                 request_response::Message::Response {
                     request_id,
                     response,
                 } => {
-                    let _ = pending_request_function
-                        .remove(&request_id)
-                        .expect("Request to still be pending.")
-                        .send(Ok(response));
+                    match self.pending_request_function.remove(&request_id) {
+                        Some(sender) => {
+                            let _ = sender.send(Ok(response));
+                        }
+                        None => warn!("Response to unknown request {:?}", request_id),
+                    }
                 }
             },
             SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
@@ -391,33 +878,212 @@ impl EventLoop {
                     request_id, error, ..
                 },
             )) => {
-                let _ = pending_request_function
-                    .remove(&request_id)
-                    .expect("Request to still be pending.")
-                    .send(Err(Box::new(error)));
+                match self.pending_request_function.remove(&request_id) {
+                    Some(sender) => {
+                        let _ = sender.send(Err(error.into()));
+                    }
+                    None => warn!("Failure of unknown request {:?}: {:?}", request_id, error),
+                }
             }
             SwarmEvent::Behaviour(BehaviourEvent::RequestResponse(
                 request_response::Event::ResponseSent { .. },
             )) => {}
+            SwarmEvent::Behaviour(BehaviourEvent::Cancel(request_response::Event::Message {
+                peer,
+                message: request_response::Message::Request { request, channel, .. },
+            })) => {
+                if self.swarm.behaviour_mut().cancel.send_response(channel, CancelResponse).is_err() {
+                    warn!("Failed to acknowledge cancellation, connection to peer closed");
+                }
+                let sent = self.event_sender
+                    .send(Event::InboundCancel {
+                        peer,
+                        request_id: request.request_id,
+                    })
+                    .await;
+                if let Err(e) = sent {
+                    error!("Failed to deliver inbound cancellation: {:?}", e);
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Cancel(request_response::Event::OutboundFailure { peer, error, .. })) => {
+                warn!("Failed to cancel request on peer {:?}: {:?}", peer, error);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Cancel(_)) => {}
+            SwarmEvent::Behaviour(BehaviourEvent::Package(request_response::Event::Message { peer, message })) => match message {
+                request_response::Message::Request { request, channel, .. } => {
+                    let sent = self.event_sender
+                        .send(Event::PackageRequested {
+                            peer,
+                            request,
+                            channel,
+                        })
+                        .await;
+                    if let Err(e) = sent {
+                        error!("Failed to deliver inbound package request: {:?}", e);
+                    }
+                }
+                request_response::Message::Response { request_id, response } => {
+                    match self.pending_request_package.remove(&request_id) {
+                        Some(sender) => {
+                            let _ = sender.send(Ok(response));
+                        }
+                        None => warn!("Package response to unknown request {:?}", request_id),
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Package(request_response::Event::OutboundFailure { request_id, error, .. })) => {
+                match self.pending_request_package.remove(&request_id) {
+                    Some(sender) => {
+                        let _ = sender.send(Err(error.into()));
+                    }
+                    None => warn!("Failure of unknown package request {:?}: {:?}", request_id, error),
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Package(_)) => {}
+            SwarmEvent::Behaviour(BehaviourEvent::Replica(request_response::Event::Message { peer, message })) => match message {
+                request_response::Message::Request { request, channel, .. } => {
+                    let sent = self.event_sender
+                        .send(Event::ReplicaRequested {
+                            peer,
+                            request,
+                            channel,
+                        })
+                        .await;
+                    if let Err(e) = sent {
+                        error!("Failed to deliver inbound replica request: {:?}", e);
+                    }
+                }
+                request_response::Message::Response { request_id, response } => {
+                    match self.pending_request_replica.remove(&request_id) {
+                        Some(sender) => {
+                            let _ = sender.send(Ok(response));
+                        }
+                        None => warn!("Replica response to unknown request {:?}", request_id),
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Replica(request_response::Event::OutboundFailure { request_id, error, .. })) => {
+                match self.pending_request_replica.remove(&request_id) {
+                    Some(sender) => {
+                        let _ = sender.send(Err(error.into()));
+                    }
+                    None => warn!("Failure of unknown replica request {:?}: {:?}", request_id, error),
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Replica(_)) => {}
+            SwarmEvent::Behaviour(BehaviourEvent::Deploy(request_response::Event::Message { peer, message })) => match message {
+                request_response::Message::Request { request, channel, .. } => {
+                    let sent = self.event_sender
+                        .send(Event::DeployRequested {
+                            peer,
+                            request,
+                            channel,
+                        })
+                        .await;
+                    if let Err(e) = sent {
+                        error!("Failed to deliver inbound deploy request: {:?}", e);
+                    }
+                }
+                request_response::Message::Response { request_id, response } => {
+                    match self.pending_request_deploy.remove(&request_id) {
+                        Some(sender) => {
+                            let _ = sender.send(Ok(response));
+                        }
+                        None => warn!("Deploy response to unknown request {:?}", request_id),
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Deploy(request_response::Event::OutboundFailure { request_id, error, .. })) => {
+                match self.pending_request_deploy.remove(&request_id) {
+                    Some(sender) => {
+                        let _ = sender.send(Err(error.into()));
+                    }
+                    None => warn!("Failure of unknown deploy request {:?}: {:?}", request_id, error),
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Deploy(_)) => {}
             SwarmEvent::Behaviour(BehaviourEvent::Identify(IdentifyEvent::Received {
                 peer_id,
                 info,
             })) => {
                 info!("Received identify info from peer {:?}: {:?}", peer_id, info);
-                info.clone().listen_addrs.iter().for_each(|addr| {
+                // Add QUIC addresses first so that they are preferred when dialing the peer.
+                let listen_addrs = prefer_quic(info.listen_addrs);
+                listen_addrs.iter().for_each(|addr| {
                     self.swarm
                         .behaviour_mut()
                         .kademlia
                         .add_address(&peer_id, addr.clone());
                 });
+                self.peer_addrs.insert(peer_id, listen_addrs);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(mut peers))) => {
+                peers.sort_by_key(|(_, address)| !is_quic(address));
+                for (peer_id, address) in peers {
+                    info!("Discovered peer {:?} in the local network at {:?}", peer_id, address);
+                    self.add_peer_addr(peer_id, address.clone());
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .add_address(&peer_id, address);
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Expired(peers))) => {
+                for (peer_id, address) in peers {
+                    info!("Local network address {:?} of peer {:?} expired", address, peer_id);
+                }
             }
+            SwarmEvent::Behaviour(BehaviourEvent::Autonat(autonat::Event::StatusChanged { old, new })) => {
+                info!("NAT status changed from {:?} to {:?}", old, new);
+                match new {
+                    autonat::NatStatus::Private => self.listen_on_relays(),
+                    autonat::NatStatus::Public(_) => self.stop_listening_on_relays(),
+                    autonat::NatStatus::Unknown => {}
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Autonat(_)) => {}
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::ReservationReqAccepted { relay_peer_id, .. },
+            )) => {
+                info!("Reservation accepted by relay {:?}", relay_peer_id);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(e)) => info!("Relay client event: {:?}", e),
+            SwarmEvent::Behaviour(BehaviourEvent::RelayServer(e)) => info!("Relay server event: {:?}", e),
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(dcutr::Event { remote_peer_id, result })) => match result {
+                Ok(connection_id) => info!("Hole punched to peer {:?} on connection {:?}", remote_peer_id, connection_id),
+                Err(e) => warn!("Hole punching to peer {:?} failed: {:?}", remote_peer_id, e),
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Ping(ping::Event { peer, result, .. })) => match result {
+                Ok(rtt) => self.latencies.lock().await.record(peer, rtt),
+                Err(e) => warn!("Ping to peer {:?} failed: {:?}", peer, e),
+            },
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message { message, .. })) => {
+                let Some(peer) = message.source else {
+                    return;
+                };
+                match serde_json::from_slice::<LoadReport>(&message.data) {
+                    Ok(report) => self.loads.lock().await.insert(peer, report),
+                    Err(e) => warn!("Invalid load report from peer {:?}: {:?}", peer, e),
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(_)) => {}
             SwarmEvent::NewListenAddr { address, .. } => {
+                // Advertise relayed addresses, they are the only way to reach a node behind a NAT.
+                if address.iter().any(|protocol| protocol == Protocol::P2pCircuit) {
+                    self.swarm.add_external_address(address.clone());
+                }
                 let local_peer_id = *self.swarm.local_peer_id();
                 info!(
                     "Local node is listening on {:?}",
                     address.with(Protocol::P2p(local_peer_id))
                 );
             }
+            SwarmEvent::ExpiredListenAddr { address, .. } => {
+                if address.iter().any(|protocol| protocol == Protocol::P2pCircuit) {
+                    self.swarm.remove_external_address(&address);
+                }
+                info!("Local node stopped listening on {:?}", address);
+            }
             SwarmEvent::NewExternalAddrOfPeer { peer_id, address, .. } => {
                 info!("Discovered external address {:?} for peer {:?}", address, peer_id);
             }
@@ -426,9 +1092,12 @@ impl EventLoop {
                 peer_id, endpoint, ..
             } => {
                 info!("Connected to peer: {:?}", peer_id.to_base58());
+                self.quic_dials.remove(&peer_id);
                 if endpoint.is_dialer() {
-                    if let Some(sender) = pending_dial.remove(&peer_id) {
+                    if let Some(sender) = self.pending_dial.remove(&peer_id) {
                         let _ = sender.send(Ok(()));
+                        // Fill the routing table through the newly joined peer.
+                        self.bootstrap();
                     }
                 }
                 // Add dialing node to dialed node routing table. (Enable bootstrap node to get_providers)
@@ -438,13 +1107,21 @@ impl EventLoop {
                         .kademlia
                         .add_address(&peer_id, endpoint.get_remote_address().clone());
                 }
+                self.provider_dial_finished(&peer_id);
+            }
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                self.latencies.lock().await.remove(&peer_id);
             }
             SwarmEvent::ConnectionClosed { .. } => {}
             SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
                 if let Some(peer_id) = peer_id {
-                    if let Some(sender) = pending_dial.remove(&peer_id) {
-                        let _ = sender.send(Err(Box::new(error)));
+                    if self.quic_dials.remove(&peer_id) && self.dial_fallback(peer_id, &error) {
+                        return;
+                    }
+                    if let Some(sender) = self.pending_dial.remove(&peer_id) {
+                        let _ = sender.send(Err(FaasError::Network(format!("Failed to dial peer {}: {}", peer_id, error))));
                     }
+                    self.provider_dial_finished(&peer_id);
                 }
             }
             SwarmEvent::IncomingConnectionError { .. } => {}
@@ -456,16 +1133,141 @@ impl EventLoop {
         }
     }
 
+    /// Refresh the Kademlia routing table so that it heals after peers leave or join.
+    fn bootstrap(&mut self) {
+        match self.swarm.behaviour_mut().kademlia.bootstrap() {
+            Ok(query_id) => info!("Started Kademlia bootstrap {:?}", query_id),
+            Err(kad::NoKnownPeers()) => info!("Skipping Kademlia bootstrap, no known peers"),
+        }
+    }
+
+    /// Listen through the configured relays, reserving a slot on each of them.
+    fn listen_on_relays(&mut self) {
+        if !self.relay_listeners.is_empty() {
+            return;
+        }
+        for relay in self.relays.clone() {
+            match self.swarm.listen_on(relay.clone().with(Protocol::P2pCircuit)) {
+                Ok(listener_id) => {
+                    info!("Listening through relay {:?}", relay);
+                    self.relay_listeners.push(listener_id);
+                }
+                Err(e) => error!("Failed to listen through relay {:?}: {:?}", relay, e),
+            }
+        }
+    }
+
+    /// Stop listening through relays once the node is reachable directly.
+    fn stop_listening_on_relays(&mut self) {
+        for listener_id in self.relay_listeners.drain(..) {
+            self.swarm.remove_listener(listener_id);
+        }
+    }
+
+    /// Dial the providers not connected yet, answering the lookup once every dial
+    /// finished or the dial deadline passed, without blocking the event loop.
+    fn dial_providers(&mut self, providers: HashSet<PeerId>, sender: oneshot::Sender<HashSet<PeerId>>) {
+        let mut dialing = HashSet::new();
+        for provider in &providers {
+            if !self.swarm.is_connected(provider) {
+                match self.dial_peer(*provider) {
+                    Ok(()) => {
+                        info!("Dialing provider {:?}", provider);
+                        dialing.insert(*provider);
+                    }
+                    Err(e) => {
+                        error!("Error dialing provider {:?}: {:?}", provider, e);
+                    }
+                }
+            }
+        }
+
+        if dialing.is_empty() {
+            let _ = sender.send(providers);
+            return;
+        }
+        // Dial deadline has to be lower than the get providers timeout
+        self.pending_provider_dials.push(PendingProviderDials {
+            providers,
+            dialing,
+            deadline: Instant::now() + Duration::from_secs(3),
+            sender,
+        });
+    }
+
+    /// Dial the peer over QUIC only when it listens on QUIC addresses, as the swarm would otherwise
+    /// dial all of its addresses concurrently and keep whichever connection comes first.
+    fn dial_peer(&mut self, peer_id: PeerId) -> Result<(), DialError> {
+        let quic_addrs: Vec<_> = self.peer_addrs
+            .get(&peer_id)
+            .map(|addrs| addrs.iter().filter(|addr| is_quic(addr)).cloned().collect())
+            .unwrap_or_default();
+        if quic_addrs.is_empty() {
+            return self.swarm.dial(peer_id);
+        }
+        self.swarm.dial(DialOpts::peer_id(peer_id).addresses(quic_addrs).build())?;
+        self.quic_dials.insert(peer_id);
+        Ok(())
+    }
+
+    /// Dial the peer over its other addresses after dialing it over QUIC failed.
+    /// Returns whether a new dial started.
+    fn dial_fallback(&mut self, peer_id: PeerId, error: &DialError) -> bool {
+        let addrs: Vec<_> = self.peer_addrs
+            .get(&peer_id)
+            .map(|addrs| addrs.iter().filter(|addr| !is_quic(addr)).cloned().collect())
+            .unwrap_or_default();
+        if addrs.is_empty() {
+            return false;
+        }
+        warn!("Failed to dial peer {:?} over QUIC, falling back to its other addresses: {}", peer_id, error);
+        match self.swarm.dial(DialOpts::peer_id(peer_id).addresses(addrs).build()) {
+            Ok(()) => true,
+            Err(e) => {
+                error!("Error dialing peer {:?}: {:?}", peer_id, e);
+                false
+            }
+        }
+    }
+
+    fn add_peer_addr(&mut self, peer_id: PeerId, addr: Multiaddr) {
+        let addrs = self.peer_addrs.entry(peer_id).or_default();
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    fn provider_dial_finished(&mut self, peer_id: &PeerId) {
+        for pending in self.pending_provider_dials.iter_mut() {
+            pending.dialing.remove(peer_id);
+        }
+        self.resolve_provider_dials(|pending| pending.dialing.is_empty());
+    }
+
+    fn expire_provider_dials(&mut self) {
+        let now = Instant::now();
+        self.resolve_provider_dials(|pending| pending.deadline <= now);
+    }
+
+    fn resolve_provider_dials(&mut self, is_resolved: impl Fn(&PendingProviderDials) -> bool) {
+        let (resolved, pending): (Vec<_>, Vec<_>) = self.pending_provider_dials
+            .drain(..)
+            .partition(|pending| is_resolved(pending));
+        self.pending_provider_dials = pending;
+        for dials in resolved {
+            if !dials.dialing.is_empty() {
+                warn!("Dials to providers {:?} did not finish in time", dials.dialing);
+            }
+            let _ = dials.sender.send(dials.providers);
+        }
+    }
+
     async fn handle_command(&mut self, command: Command) {
-        let mut pending_dial = self.pending_dial.lock().await;
-        let mut pending_start_providing = self.pending_start_providing.lock().await;
-        let mut pending_get_providers = self.pending_get_providers.lock().await;
-        let mut pending_request_function = self.pending_request_function.lock().await;
         match command {
             Command::StartListening { addr, sender } => {
-                let _ = match self.swarm.listen_on(addr) {
+                let _ = match self.swarm.listen_on(addr.clone()) {
                     Ok(_) => sender.send(Ok(())),
-                    Err(e) => sender.send(Err(Box::new(e))),
+                    Err(e) => sender.send(Err(FaasError::Network(format!("Failed to listen on {}: {}", addr, e)))),
                 };
             }
             Command::Dial {
@@ -473,7 +1275,11 @@ impl EventLoop {
                 peer_addr,
                 sender,
             } => {
-                if let hash_map::Entry::Vacant(e) = pending_dial.entry(peer_id) {
+                if let hash_map::Entry::Vacant(e) = self.pending_dial.entry(peer_id) {
+                    let addrs = self.peer_addrs.entry(peer_id).or_default();
+                    if !addrs.contains(&peer_addr) {
+                        addrs.push(peer_addr.clone());
+                    }
                     self.swarm
                         .behaviour_mut()
                         .kademlia
@@ -483,33 +1289,87 @@ impl EventLoop {
                             e.insert(sender);
                         }
                         Err(e) => {
-                            let _ = sender.send(Err(Box::new(e)));
+                            let _ = sender.send(Err(FaasError::Network(format!("Failed to dial peer {}: {}", peer_id, e))));
                         }
                     }
                 } else {
-                    todo!("Already dialing peer.");
+                    let _ = sender.send(Err(FaasError::BadInput(format!("Already dialing peer {}", peer_id))));
                 }
             }
             Command::StartProviding { function_name, sender } => {
-                let query_id = self
+                match self
                     .swarm
                     .behaviour_mut()
                     .kademlia
                     .start_providing(function_name.into_bytes().into())
-                    .expect("No store error.");
-                pending_start_providing.insert(query_id, sender);
+                {
+                    Ok(query_id) => {
+                        self.pending_start_providing.insert(query_id, sender);
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(FaasError::Internal(format!("Failed to store provider record: {:?}", e))));
+                    }
+                }
+            }
+            Command::StopProviding { function_name } => {
+                self.swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .stop_providing(&function_name.clone().into_bytes().into());
+                info!("Stopped providing function: {:?}", function_name);
+            }
+            Command::StopProvidingAll { sender } => {
+                let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+                let keys: Vec<kad::RecordKey> = kademlia
+                    .store_mut()
+                    .provided()
+                    .map(|record| record.key.clone())
+                    .collect();
+                let mut function_names = Vec::new();
+                for key in keys {
+                    kademlia.stop_providing(&key);
+                    function_names.push(String::from_utf8_lossy(key.as_ref()).into_owned());
+                }
+                info!("Stopped providing functions: {:?}", function_names);
+                let _ = sender.send(function_names);
             }
+            Command::ProvidedFunctions { sender } => {
+                let function_names = self.swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .store_mut()
+                    .provided()
+                    .map(|record| String::from_utf8_lossy(record.key.as_ref()).into_owned())
+                    .collect();
+                let _ = sender.send(function_names);
+            }
+            Command::PublishLoadReport { report } => {
+                let data = match serde_json::to_vec(&report) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        error!("Failed to encode load report: {:?}", e);
+                        return;
+                    }
+                };
+                if let Err(e) = self.swarm
+                    .behaviour_mut()
+                    .gossipsub
+                    .publish(IdentTopic::new(LOAD_REPORT_TOPIC), data)
+                {
+                    // Expected while no peer is subscribed yet.
+                    info!("Load report not published: {:?}", e);
+                }
+            }
+            Command::Shutdown => {}
             Command::GetProviders { function_name, sender } => {
                 let query_id = self.swarm
                     .behaviour_mut()
                     .kademlia
                     .get_providers(function_name.into_bytes().into());
-                pending_get_providers.insert(query_id, sender);
+                self.pending_get_providers.insert(query_id, sender);
             }
             Command::RequestFunction {
-                function_name,
-                method,
-                body,
+                request,
                 peer,
                 sender,
             } => {
@@ -517,17 +1377,80 @@ impl EventLoop {
                     .swarm
                     .behaviour_mut()
                     .request_response
-                    .send_request(&peer, FunctionRequest(function_name, method, body));
-                pending_request_function.insert(request_id, sender);
+                    .send_request(&peer, request);
+                self.pending_request_function.insert(request_id, sender);
                 info!("Request {:?} stored", request_id);
             }
-            Command::RespondFunction { function_response_status, function_response_body, channel } => {
-                info!("Command RespondFunction");
+            Command::CancelFunction { peer, request_id } => {
                 self.swarm
+                    .behaviour_mut()
+                    .cancel
+                    .send_request(&peer, CancelRequest { request_id });
+            }
+            Command::RequestPackage { request, peer, sender } => {
+                let request_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .package
+                    .send_request(&peer, request);
+                self.pending_request_package.insert(request_id, sender);
+            }
+            Command::RequestDeploy { request, peer, sender } => {
+                let request_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .deploy
+                    .send_request(&peer, request);
+                self.pending_request_deploy.insert(request_id, sender);
+            }
+            Command::RespondDeploy { response, channel } => {
+                if self.swarm
+                    .behaviour_mut()
+                    .deploy
+                    .send_response(channel, response)
+                    .is_err()
+                {
+                    warn!("Failed to answer deploy request, connection to peer closed");
+                }
+            }
+            Command::RequestReplica { request, peer, sender } => {
+                let request_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .replica
+                    .send_request(&peer, request);
+                self.pending_request_replica.insert(request_id, sender);
+            }
+            Command::RespondReplica { response, channel } => {
+                if self.swarm
+                    .behaviour_mut()
+                    .replica
+                    .send_response(channel, response)
+                    .is_err()
+                {
+                    warn!("Failed to answer replica request, connection to peer closed");
+                }
+            }
+            Command::RespondPackage { response, channel } => {
+                if self.swarm
+                    .behaviour_mut()
+                    .package
+                    .send_response(channel, response)
+                    .is_err()
+                {
+                    warn!("Failed to send package, connection to peer closed");
+                }
+            }
+            Command::RespondFunction { response, channel } => {
+                info!("Command RespondFunction");
+                if self.swarm
                     .behaviour_mut()
                     .request_response
-                    .send_response(channel, FunctionResponse(function_response_status, function_response_body))
-                    .expect("Connection to peer to be still open.");
+                    .send_response(channel, response)
+                    .is_err()
+                {
+                    warn!("Failed to send response, connection to peer closed");
+                }
             }
         }
     }
@@ -535,56 +1458,122 @@ impl EventLoop {
 
 #[derive(NetworkBehaviour)]
 struct Behaviour {
-    request_response: request_response::cbor::Behaviour<FunctionRequest, FunctionResponse>,
+    request_response: request_response::Behaviour<FunctionCodec>,
+    cancel: request_response::cbor::Behaviour<CancelRequest, CancelResponse>,
+    package: request_response::cbor::Behaviour<PackageRequest, PackageResponse>,
+    replica: request_response::cbor::Behaviour<ReplicaRequest, ReplicaResponse>,
+    deploy: request_response::cbor::Behaviour<DeployRequest, DeployResponse>,
     kademlia: kad::Behaviour<kad::store::MemoryStore>,
     identify: IdentifyBehavior,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    relay_client: relay::client::Behaviour,
+    relay_server: Toggle<relay::Behaviour>,
+    autonat: autonat::Behaviour,
+    dcutr: dcutr::Behaviour,
+    ping: ping::Behaviour,
+    gossipsub: gossipsub::Behaviour,
+    stream: libp2p_stream::Behaviour,
+    connection_limits: connection_limits::Behaviour,
 }
 
 #[derive(Debug)]
 enum Command {
     StartListening {
         addr: Multiaddr,
-        sender: oneshot::Sender<Result<(), Box<dyn Error + Send>>>,
+        sender: PendingSender<()>,
     },
     Dial {
         peer_id: PeerId,
         peer_addr: Multiaddr,
-        sender: oneshot::Sender<Result<(), Box<dyn Error + Send>>>,
+        sender: PendingSender<()>,
     },
     StartProviding {
         function_name: String,
-        sender: oneshot::Sender<()>,
+        sender: PendingSender<()>,
+    },
+    StopProviding {
+        function_name: String,
+    },
+    StopProvidingAll {
+        sender: oneshot::Sender<Vec<String>>,
+    },
+    ProvidedFunctions {
+        sender: oneshot::Sender<Vec<String>>,
+    },
+    PublishLoadReport {
+        report: LoadReport,
     },
+    Shutdown,
     GetProviders {
         function_name: String,
         sender: oneshot::Sender<HashSet<PeerId>>,
     },
     RequestFunction {
-        function_name: String,
-        method: String,
-        body: Option<Vec<u8>>,
+        request: FunctionRequest,
+        peer: PeerId,
+        sender: PendingSender<FunctionResponse>,
+    },
+    CancelFunction {
         peer: PeerId,
-        sender: oneshot::Sender<Result<FunctionResponse, Box<dyn Error + Send>>>,
+        request_id: String,
     },
     RespondFunction {
-        function_response_status: u16,
-        function_response_body: Vec<u8>,
+        response: FunctionResponse,
         channel: ResponseChannel<FunctionResponse>,
     },
+    RequestPackage {
+        request: PackageRequest,
+        peer: PeerId,
+        sender: PendingSender<PackageResponse>,
+    },
+    RespondPackage {
+        response: PackageResponse,
+        channel: ResponseChannel<PackageResponse>,
+    },
+    RequestReplica {
+        request: ReplicaRequest,
+        peer: PeerId,
+        sender: PendingSender<ReplicaResponse>,
+    },
+    RequestDeploy {
+        request: DeployRequest,
+        peer: PeerId,
+        sender: PendingSender<DeployResponse>,
+    },
+    RespondDeploy {
+        response: DeployResponse,
+        channel: ResponseChannel<DeployResponse>,
+    },
+    RespondReplica {
+        response: ReplicaResponse,
+        channel: ResponseChannel<ReplicaResponse>,
+    },
 }
 
 #[derive(Debug)]
 pub(crate) enum Event {
     InboundRequest {
-        request: String,
-        method: String,
-        body: Option<Vec<u8>>,
+        peer: PeerId,
+        request: FunctionRequest,
         channel: ResponseChannel<FunctionResponse>,
     },
-}
-
-// Simple function exchange protocol
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-struct FunctionRequest(String,String,Option<Vec<u8>>);
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub(crate) struct FunctionResponse(pub u16, pub Vec<u8>);
\ No newline at end of file
+    InboundCancel {
+        peer: PeerId,
+        request_id: String,
+    },
+    PackageRequested {
+        peer: PeerId,
+        request: PackageRequest,
+        channel: ResponseChannel<PackageResponse>,
+    },
+    ReplicaRequested {
+        peer: PeerId,
+        request: ReplicaRequest,
+        channel: ResponseChannel<ReplicaResponse>,
+    },
+    DeployRequested {
+        peer: PeerId,
+        request: DeployRequest,
+        channel: ResponseChannel<DeployResponse>,
+    },
+}
\ No newline at end of file
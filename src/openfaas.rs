@@ -1,22 +1,33 @@
-use actix_multipart::Multipart;
-use futures::StreamExt;
+use futures::channel::mpsc;
+use futures::{SinkExt, StreamExt, TryStreamExt};
 //apt install libssl-dev
-use reqwest::{Client, Response};
-use std::error::Error;
+use reqwest::{Body, Client, Method};
 use std::fs;
 use std::fs::File;
-use std::io::{self, Write};
+use std::io::Write;
 use std::path::Path;
-use std::process::Command;
+use std::time::Duration;
 use regex::Regex;
+use tokio::process::Command;
+use tokio::sync::Mutex;
 use uuid::Uuid;
 
 use log::{info, error};
 
+use crate::error::FaasError;
+use crate::model::OpenFaaSResponse;
+use crate::package::FunctionPackage;
+use crate::streaming::BodyStream;
+
+/// Folder with the files of the function template, completed with the uploaded files on each build.
+const HANDLER_DIR: &str = "openfaas_handler";
+
 pub(crate) struct OpenFaasClient {
     http_client: Client,
     host: String,
-    docker_username: String
+    docker_username: String,
+    // Held during deployments and removals so that faas-cli runs one at a time
+    deploy_lock: Mutex<()>,
 }
 
 impl OpenFaasClient {
@@ -24,57 +35,98 @@ impl OpenFaasClient {
         let client = Client::new();
         OpenFaasClient {
                 http_client: client,
-                host: host,
-                docker_username: docker_username
+                host,
+                docker_username,
+                deploy_lock: Mutex::new(()),
         }
     }
     
     pub(crate) async fn request_function(
         &self,
         function_name: &String,
-        method: &String,
-        body: Option<Vec<u8>>
-    ) -> Result<Response, Box<dyn Error + Send>> {
-        let resp;
-        match method.as_str() {
+        method: &str,
+        body: Option<Vec<u8>>,
+        timeout: Option<Duration>,
+    ) -> Result<OpenFaaSResponse, FaasError> {
+        let url = format!("{}/function/{}", self.host, function_name);
+        let request = match method {
             "GET" => {
-                resp = self.http_client.get(format!("{}/function/{}", self.host, function_name)).send().await;
+                self.http_client.get(url)
             },
             "POST" => {
-                if body == None {
-                    return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Body is required for POST method")));
-                }
-                resp = self.http_client.post(format!("{}/function/{}", self.host, function_name)).body(body.unwrap()).send().await;
+                let Some(body) = body else {
+                    return Err(FaasError::BadInput("Body is required for POST method".to_string()));
+                };
+                self.http_client.post(url).body(body)
             },
             "PUT" => {
-                if body == None {
-                    return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Body is required for PUT method")));
-                }
-                resp = self.http_client.put(format!("{}/function/{}", self.host, function_name)).body(body.unwrap()).send().await;
+                let Some(body) = body else {
+                    return Err(FaasError::BadInput("Body is required for PUT method".to_string()));
+                };
+                self.http_client.put(url).body(body)
             },
             "DELETE" => {
-                resp = self.http_client.delete(format!("{}/function/{}", self.host, function_name)).send().await;
+                self.http_client.delete(url)
             },
             "PATCH" => {
-                if body == None {
-                    return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Body is required for PATCH method")));
-                }
-                resp = self.http_client.patch(format!("{}/function/{}", self.host, function_name)).body(body.unwrap()).send().await;
+                let Some(body) = body else {
+                    return Err(FaasError::BadInput("Body is required for PATCH method".to_string()));
+                };
+                self.http_client.patch(url).body(body)
             },
             _ => {
-                return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Not supported method")));
+                return Err(FaasError::BadInput("Not supported method".to_string()));
             }
-        }
-        match resp {
-            Ok(response) => Ok(response),
-            Err(e) => Err(Box::new(e)),
-        }
+        };
+        // Give up on the function once the deadline of the caller passed.
+        let request = match timeout {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
+        };
+        let resp = request.send().await;
+        let resp = resp?;
+        let status = resp.status().as_u16();
+        let body = resp.bytes().await?.to_vec();
+        Ok(OpenFaaSResponse { status, body })
     }
+    /// Request the function streaming the request and response bodies.
+    pub(crate) async fn request_function_stream(
+        &self,
+        function_name: &str,
+        method: &str,
+        body: BodyStream,
+        timeout: Option<Duration>,
+    ) -> Result<(u16, BodyStream), FaasError> {
+        let method = Method::from_bytes(method.as_bytes())
+            .map_err(|_| FaasError::BadInput(format!("Invalid HTTP method: {}", method)))?;
+        // The HTTP client needs a body it can share between threads, forward the chunks through a channel.
+        let (mut sender, receiver) = mpsc::channel(8);
+        tokio::spawn(async move {
+            let mut body = body;
+            while let Some(chunk) = body.next().await {
+                if sender.send(chunk).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let request = self.http_client
+            .request(method, format!("{}/function/{}", self.host, function_name))
+            .body(Body::wrap_stream(receiver));
+        // Give up on the function once the deadline of the caller passed.
+        let request = match timeout {
+            Some(timeout) => request.timeout(timeout),
+            None => request,
+        };
+        let resp = request.send().await?;
+        let status = resp.status().as_u16();
+        Ok((status, resp.bytes_stream().map_err(FaasError::from).boxed()))
+    }
+
     pub(crate) async fn deploy_function(
         &self,
-        mut files: Multipart,
+        package: &FunctionPackage,
         optional_function_name: Option<&str>,
-    ) -> Result<String, Box<dyn Error>>{
+    ) -> Result<String, FaasError>{
         let my_uuid = Uuid::new_v4();
 
         let generated_function_name;
@@ -82,98 +134,73 @@ impl OpenFaasClient {
             Some(name) => name,
             None => {
                 // add fn- to uuid to create funciton name
-                generated_function_name = format!("fn-{}", my_uuid.to_string());
+                generated_function_name = format!("fn-{}", my_uuid);
                 &generated_function_name
             }
         };
-        
-        let template_path = Path::new("openfaas_config_template.yml");
-        let output_path = Path::new("openfaas_config.yml");
-        
-        let mut values = vec![];
-        let docker_username = &self.docker_username.clone();
-        values.push(function_name);
-        values.push(docker_username);
-        values.push(function_name);
 
-        match self.create_config_file(template_path, output_path, &values) {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Failed to create config file: {:?}", e);
-                return Err(Box::new(e));
-            }
+        // Builds run one at a time, each in its own directory, so that concurrent deployments
+        // from the API and from peers do not overwrite each other's files.
+        let _deploy = self.deploy_lock.lock().await;
+        let build_dir = std::env::temp_dir().join(format!("openfaas-build-{}", my_uuid));
+        let result = self.build_and_deploy(&build_dir, package, function_name).await;
+        if let Err(e) = fs::remove_dir_all(&build_dir) {
+            error!("Failed to remove build directory {:?}: {:?}", build_dir, e);
         }
-        
-        // Store multipart files handler.py and requirements.txt inside openfaas_handler folder
-        let mut handler = false;
-        let mut requirements = false;
-        while let Some(field) = files.next().await {
-            let mut field = match field {
-                Ok(field) => field,
-                Err(e) => return Err(Box::new(e)),
-            };
-            
-            let field_name = field.name().to_owned();
+        result?;
+        Ok(function_name.to_owned())
+    }
 
-            if field_name != "handler" && field_name != "requirements" {
-                error!("Invalid field name: {}", field_name);
-                let _ = self.remove_files();
-                return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "Invalid field name")));
-            
+    async fn build_and_deploy(&self, build_dir: &Path, package: &FunctionPackage, function_name: &str) -> Result<(), FaasError> {
+        // The handler folder holds the files of the template, e.g. tox.ini, and the uploaded files.
+        let handler_dir = build_dir.join(HANDLER_DIR);
+        fs::create_dir_all(&handler_dir)?;
+        for entry in fs::read_dir(HANDLER_DIR)? {
+            let path = entry?.path();
+            if let (true, Some(file_name)) = (path.is_file(), path.file_name()) {
+                fs::copy(&path, handler_dir.join(file_name))?;
             }
-            
-            if field_name == "handler" && handler || field_name == "requirements" && requirements {
-                error!("Handler file already uploaded");
-                return Err(Box::new(io::Error::new(io::ErrorKind::AlreadyExists, "Handler file already uploaded")));
+        }
+        for (file_name, content) in [("handler.py", &package.handler), ("requirements.txt", &package.requirements)] {
+            if let Err(e) = fs::write(handler_dir.join(file_name), content) {
+                error!("Failed to write {}: {:?}", file_name, e);
+                return Err(e.into());
             }
-            let field_extension = if field_name == "handler" { "py" } else { "txt" };
-            let mut file = match File::create(format!("openfaas_handler/{}.{}", field_name, field_extension)) {
-                Ok(file) => file,
-                Err(e) => {
-                    error!("Failed to create file: {:?}", e);
-                    return Err(Box::new(e));
-                }
-            };
-            // Write the file content to the file
-            while let Some(chunk) = field.next().await {
-                let chunk = match chunk {
-                    Ok(chunk) => chunk,
-                    Err(e) => return Err(Box::new(e)),
-                };
+        }
 
-                match file.write_all(&chunk) {
-                    Ok(_) => if field_name == "handler" {handler = true} else {requirements = true},
-                    Err(e) => {
-                        error!("Failed to write to output file: {:?}", e);
-                        return Err(Box::new(e));
-                    }
-                }
-            }
-            
-            
+        let handler_path = handler_dir.to_string_lossy();
+        let values = [function_name, &handler_path, &self.docker_username, function_name];
+        let config_path = build_dir.join("openfaas_config.yml");
+        if let Err(e) = self.create_config_file(Path::new("openfaas_config_template.yml"), &config_path, &values) {
+            error!("Failed to create config file: {:?}", e);
+            return Err(e.into());
         }
-    
-        // Deploy the function to openfaas
-        let output_result = Command::new("faas-cli").args(["up", "-f", "openfaas_config.yml"]).output();
-        match output_result {
-            Ok(output) => {
-                if output.status.success() {
-                    info!("Function deployed successfully");
-                } else {
-                    error!("Failed to deploy function: {:?}", output);
-                    self.remove_files().unwrap();
-                    return Err(Box::new(io::Error::new(io::ErrorKind::Other, "Failed to deploy function. Check handler and requirements files")));
-                }
-            },
-            Err(e) => {
+
+        // Deploy the function to openfaas, without blocking the runtime during the image build
+        let output = Command::new("faas-cli").arg("up").arg("-f").arg(&config_path).output().await
+            .map_err(|e| {
                 error!("Failed to execute command: {:?}", e);
-                return Err(Box::new(e));
-            }
-        };
-        
-        // Remove files handler.py and requirements.txt from openfaas_handler folder
-        self.remove_files().unwrap();
-        Ok(function_name.to_owned())
+                FaasError::BackendBuild(format!("Failed to execute faas-cli: {}", e))
+            })?;
+        if !output.status.success() {
+            error!("Failed to deploy function: {:?}", output);
+            return Err(FaasError::BackendBuild("Failed to deploy function. Check handler and requirements files".to_string()));
+        }
+        info!("Function deployed successfully");
+        Ok(())
+    }
+
+    /// Remove the function from OpenFaaS.
+    pub(crate) async fn remove_function(&self, function_name: &str) -> Result<(), FaasError> {
+        let _deploy = self.deploy_lock.lock().await;
+        let output = Command::new("faas-cli").args(["remove", function_name]).output().await
+            .map_err(|e| FaasError::BackendBuild(format!("Failed to execute faas-cli: {}", e)))?;
+        if !output.status.success() {
+            error!("Failed to remove function: {:?}", output);
+            return Err(FaasError::BackendBuild(format!("Failed to remove function {}", function_name)));
+        }
+        info!("Function {} removed", function_name);
+        Ok(())
     }
 
     fn create_config_file(
@@ -190,7 +217,7 @@ impl OpenFaasClient {
             }
         };
         // Create a regex pattern to match the placeholders
-        let re = Regex::new(r"\{\}").unwrap();
+        let re = Regex::new(r"\{\}").map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
     
         // Replace all placeholders with values from the list
         let mut result_content = template_content.clone();
@@ -216,20 +243,4 @@ impl OpenFaasClient {
     
         Ok(())
     }
-
-    fn remove_files(
-        &self) -> std::io::Result<()> {
-        let dir = fs::read_dir("openfaas_handler").unwrap();
-        for entry in dir {
-            let entry = entry.unwrap();
-            let path = entry.path();
-            if path.is_file() && path.file_name().unwrap() != "tox.ini"{
-                fs::remove_file(path).unwrap();
-            }
-        }
-        // Remove openfaas_config.yml
-        fs::remove_file("openfaas_config.yml").unwrap();
-        Ok(())
-    }
 }
-
@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex as StdMutex, PoisonError};
+
+use actix_multipart::Multipart;
+use futures::StreamExt;
+use libp2p::StreamProtocol;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::FaasError;
+
+use log::error;
+
+/// Protocol to fetch the deployment package of a function from one of its providers.
+pub(crate) const PACKAGE_PROTOCOL: StreamProtocol = StreamProtocol::new("/function-package/1");
+
+/// Files needed to deploy a function, as uploaded by the user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FunctionPackage {
+    pub handler: Vec<u8>,
+    pub requirements: Vec<u8>,
+}
+
+impl FunctionPackage {
+    /// Read the package from a multipart form with the handler and requirements files.
+    pub(crate) async fn from_multipart(mut files: Multipart) -> Result<Self, FaasError> {
+        let mut handler = None;
+        let mut requirements = None;
+        while let Some(field) = files.next().await {
+            let mut field = field?;
+            let field_name = field.name().to_owned();
+            let content = match field_name.as_str() {
+                "handler" => &mut handler,
+                "requirements" => &mut requirements,
+                _ => {
+                    error!("Invalid field name: {}", field_name);
+                    return Err(FaasError::BadInput(format!("Invalid field name: {}", field_name)));
+                }
+            };
+            if content.is_some() {
+                return Err(FaasError::BadInput(format!("Field {} uploaded more than once", field_name)));
+            }
+            let mut data = Vec::new();
+            while let Some(chunk) = field.next().await {
+                data.extend_from_slice(&chunk?);
+            }
+            *content = Some(data);
+        }
+        Ok(Self {
+            handler: handler.unwrap_or_default(),
+            requirements: requirements.unwrap_or_default(),
+        })
+    }
+
+    /// SHA-256 of the package, as a hex string, identifying its content.
+    pub(crate) fn digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        // Prefix the handler with its length so that moving bytes between files changes the digest.
+        hasher.update((self.handler.len() as u64).to_be_bytes());
+        hasher.update(&self.handler);
+        hasher.update(&self.requirements);
+        hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PackageRequest {
+    pub function: String,
+    /// Digest of the package wanted, the latest package of the function when missing.
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum PackageResponse {
+    Found {
+        digest: String,
+        package: FunctionPackage,
+        /// Number of providers the function must keep.
+        #[serde(default = "default_min_replicas")]
+        min_replicas: usize,
+    },
+    NotFound,
+    Forbidden,
+}
+
+/// Protocol to ask a peer to deploy a replica of a function, fetching its package from the providers.
+pub(crate) const REPLICA_PROTOCOL: StreamProtocol = StreamProtocol::new("/function-replica/1");
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ReplicaRequest {
+    pub function: String,
+    #[serde(default)]
+    pub reason: ReplicaReason,
+    /// Digest of the package to deploy, which the package fetched from the providers must match.
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
+/// Why a peer asks for a replica.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub(crate) enum ReplicaReason {
+    /// The function is hot, the replica is removed once idle.
+    #[default]
+    Demand,
+    /// The function has fewer providers than its replication factor, the replica is kept.
+    ReplicationFactor,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ReplicaResponse {
+    /// Whether the peer is deploying the replica.
+    pub accepted: bool,
+}
+
+/// Protocol to deploy a function on a peer, sending it the package.
+pub(crate) const DEPLOY_PROTOCOL: StreamProtocol = StreamProtocol::new("/function-deploy/1");
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DeployRequest {
+    /// Name of the function, generated by the peer when missing.
+    pub function: Option<String>,
+    pub package: FunctionPackage,
+    #[serde(default = "default_min_replicas")]
+    pub min_replicas: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum DeployResponse {
+    Deployed {
+        function: String,
+    },
+    Failed {
+        kind: String,
+        message: String,
+    },
+}
+
+/// Latest package of a function and how many providers the function must keep.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct IndexEntry {
+    digest: String,
+    #[serde(default = "default_min_replicas")]
+    min_replicas: usize,
+}
+
+fn default_min_replicas() -> usize {
+    1
+}
+
+/// Packages of the functions deployed on the node, stored on disk by digest, with an index
+/// from the function name to its latest package.
+pub(crate) struct PackageStore {
+    dir: PathBuf,
+    index: StdMutex<HashMap<String, IndexEntry>>,
+}
+
+impl PackageStore {
+    const INDEX_FILE: &'static str = "index.json";
+
+    /// Open the store in the given directory, creating it if needed.
+    pub(crate) fn open(dir: &Path) -> Result<Self, FaasError> {
+        fs::create_dir_all(dir)?;
+        let index_path = dir.join(Self::INDEX_FILE);
+        let index = if index_path.exists() {
+            serde_json::from_str(&fs::read_to_string(&index_path)?)
+                .map_err(|e| FaasError::Internal(format!("Invalid package index {:?}: {}", index_path, e)))?
+        } else {
+            HashMap::new()
+        };
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            index: StdMutex::new(index),
+        })
+    }
+
+    /// Store the package of the function and its replication factor, returning its digest.
+    pub(crate) fn insert(&self, function_name: &str, package: &FunctionPackage, min_replicas: usize) -> Result<String, FaasError> {
+        let digest = package.digest();
+        let path = self.package_path(&digest);
+        if !path.exists() {
+            let data = cbor4ii::serde::to_vec(Vec::new(), package)
+                .map_err(|e| FaasError::Internal(format!("Failed to encode package: {}", e)))?;
+            write_atomically(&path, &data)?;
+        }
+        let mut index = self.index.lock().unwrap_or_else(PoisonError::into_inner);
+        index.insert(function_name.to_string(), IndexEntry { digest: digest.clone(), min_replicas });
+        let data = serde_json::to_vec(&*index)
+            .map_err(|e| FaasError::Internal(format!("Failed to encode package index: {}", e)))?;
+        write_atomically(&self.dir.join(Self::INDEX_FILE), &data)?;
+        Ok(digest)
+    }
+
+    /// Latest package of the function with its digest, if stored.
+    pub(crate) fn get(&self, function_name: &str) -> Result<Option<(String, FunctionPackage)>, FaasError> {
+        let Some(digest) = self.digest(function_name) else {
+            return Ok(None);
+        };
+        Ok(self.get_by_digest(&digest)?.map(|package| (digest, package)))
+    }
+
+    /// Digest of the latest package of the function, if stored.
+    pub(crate) fn digest(&self, function_name: &str) -> Option<String> {
+        self.index.lock().unwrap_or_else(PoisonError::into_inner)
+            .get(function_name)
+            .map(|entry| entry.digest.clone())
+    }
+
+    /// Package with the given digest, if stored.
+    pub(crate) fn get_by_digest(&self, digest: &str) -> Result<Option<FunctionPackage>, FaasError> {
+        // The digest comes from other peers, make sure it names a file of the store.
+        if digest.len() != 64 || !digest.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(FaasError::BadInput(format!("Invalid package digest {}", digest)));
+        }
+        let path = self.package_path(digest);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = fs::read(path)?;
+        let package: FunctionPackage = cbor4ii::serde::from_slice(&data)
+            .map_err(|e| FaasError::Internal(format!("Invalid package {}: {}", digest, e)))?;
+        if package.digest() != digest {
+            return Err(FaasError::Internal(format!("Package {} is corrupted", digest)));
+        }
+        Ok(Some(package))
+    }
+
+    /// Number of providers the function must keep, 1 for the functions not stored.
+    pub(crate) fn min_replicas(&self, function_name: &str) -> usize {
+        self.index.lock().unwrap_or_else(PoisonError::into_inner)
+            .get(function_name)
+            .map_or_else(default_min_replicas, |entry| entry.min_replicas)
+    }
+
+    /// Functions stored that must keep more than one provider, with their replication factor.
+    pub(crate) fn replicated_functions(&self) -> Vec<(String, usize)> {
+        self.index.lock().unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|(_, entry)| entry.min_replicas > 1)
+            .map(|(function_name, entry)| (function_name.clone(), entry.min_replicas))
+            .collect()
+    }
+
+    fn package_path(&self, digest: &str) -> PathBuf {
+        self.dir.join(format!("{}.cbor", digest))
+    }
+}
+
+/// Write the file through a temporary file, so that readers never see it half written.
+fn write_atomically(path: &Path, data: &[u8]) -> Result<(), FaasError> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
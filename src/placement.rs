@@ -0,0 +1,248 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use libp2p::PeerId;
+
+use crate::error::FaasError;
+use crate::model::LoadReport;
+
+/// Condition on a label of a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum LabelMatch {
+    /// The node has the label with this value.
+    Equals(String, String),
+    /// The node does not have the label with this value.
+    NotEquals(String, String),
+}
+
+impl LabelMatch {
+    fn matches(&self, labels: &HashMap<String, String>) -> bool {
+        match self {
+            LabelMatch::Equals(key, value) => labels.get(key) == Some(value),
+            LabelMatch::NotEquals(key, value) => labels.get(key) != Some(value),
+        }
+    }
+}
+
+impl FromStr for LabelMatch {
+    type Err = String;
+
+    /// Parse `key=value` or `key!=value`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (key, value, equals) = match s.split_once("!=") {
+            Some((key, value)) => (key, value, false),
+            None => match s.split_once('=') {
+                Some((key, value)) => (key, value, true),
+                None => return Err(format!("Invalid label condition {}, expected key=value or key!=value", s)),
+            },
+        };
+        let (key, value) = (key.trim(), value.trim());
+        if key.is_empty() {
+            return Err(format!("Invalid label condition {}, the key is empty", s));
+        }
+        Ok(if equals {
+            LabelMatch::Equals(key.to_string(), value.to_string())
+        } else {
+            LabelMatch::NotEquals(key.to_string(), value.to_string())
+        })
+    }
+}
+
+/// Where to deploy a function: the nodes must meet every constraint, and among them the ones
+/// meeting the most preferences, then with the most free capacity, are chosen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PlacementSpec {
+    pub(crate) constraints: Vec<LabelMatch>,
+    pub(crate) preferences: Vec<LabelMatch>,
+    /// Label whose values the chosen nodes must all differ in, e.g. the zone.
+    pub(crate) spread: Option<String>,
+    /// Number of nodes to deploy on.
+    pub(crate) replicas: usize,
+}
+
+impl Default for PlacementSpec {
+    fn default() -> Self {
+        Self {
+            constraints: Vec::new(),
+            preferences: Vec::new(),
+            spread: None,
+            replicas: 1,
+        }
+    }
+}
+
+impl PlacementSpec {
+    /// Parse a comma-separated list of label conditions.
+    pub(crate) fn parse_matches(s: &str) -> Result<Vec<LabelMatch>, String> {
+        s.split(',').filter(|s| !s.trim().is_empty()).map(str::parse).collect()
+    }
+
+    /// Choose the nodes to deploy on among the given nodes and their latest load reports.
+    pub(crate) fn place(&self, nodes: Vec<(PeerId, LoadReport)>) -> Result<Vec<PeerId>, FaasError> {
+        let mut candidates: Vec<(PeerId, LoadReport)> = nodes
+            .into_iter()
+            .filter(|(_, report)| !report.is_saturated() && self.constraints.iter().all(|constraint| constraint.matches(&report.labels)))
+            .collect();
+        candidates.sort_by(|(_, a), (_, b)| {
+            let preferred = |report: &LoadReport| self.preferences.iter().filter(|preference| preference.matches(&report.labels)).count();
+            Reverse(preferred(a)).cmp(&Reverse(preferred(b)))
+                .then(Reverse(a.free_slots).cmp(&Reverse(b.free_slots)))
+                .then(Reverse(a.free_memory_mb).cmp(&Reverse(b.free_memory_mb)))
+                .then(a.cpu.total_cmp(&b.cpu))
+        });
+
+        let mut chosen = Vec::new();
+        let mut spread_values = HashSet::new();
+        for (peer, report) in candidates {
+            if chosen.len() == self.replicas {
+                break;
+            }
+            if let Some(key) = &self.spread {
+                // Nodes without the label count as a value of their own.
+                if !spread_values.insert(report.labels.get(key).cloned()) {
+                    continue;
+                }
+            }
+            chosen.push(peer);
+        }
+        if chosen.len() < self.replicas {
+            let spread = self.spread.as_ref().map(|key| format!(" with distinct {}", key)).unwrap_or_default();
+            return Err(FaasError::Unavailable(format!(
+                "Only {} of the {} nodes{} required by the placement are available",
+                chosen.len(), self.replicas, spread
+            )));
+        }
+        Ok(chosen)
+    }
+}
+
+/// Node or nodes where a function is deployed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum DeployTarget {
+    /// This node.
+    Local,
+    Peer(PeerId),
+    /// The nodes chosen by the placement engine.
+    Placement(PlacementSpec),
+}
+
+impl FromStr for DeployTarget {
+    type Err = String;
+
+    /// Parse `auto`, a peer ID or a label selector like `zone=eu,gpu!=true`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        if s == "auto" {
+            return Ok(DeployTarget::Placement(PlacementSpec::default()));
+        }
+        if s.contains('=') {
+            let constraints = PlacementSpec::parse_matches(s)?;
+            return Ok(DeployTarget::Placement(PlacementSpec { constraints, ..PlacementSpec::default() }));
+        }
+        s.parse()
+            .map(DeployTarget::Peer)
+            .map_err(|e| format!("Invalid target {}, expected auto, a peer ID or key=value labels: {}", s, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(labels: &[(&str, &str)], free_slots: u64) -> (PeerId, LoadReport) {
+        let report = LoadReport {
+            labels: labels.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect(),
+            free_slots,
+            ..LoadReport::default()
+        };
+        (PeerId::random(), report)
+    }
+
+    fn spec(constraints: &str, preferences: &str, spread: Option<&str>, replicas: usize) -> PlacementSpec {
+        PlacementSpec {
+            constraints: PlacementSpec::parse_matches(constraints).unwrap(),
+            preferences: PlacementSpec::parse_matches(preferences).unwrap(),
+            spread: spread.map(str::to_string),
+            replicas,
+        }
+    }
+
+    #[test]
+    fn label_matches_parse() {
+        assert_eq!("zone = eu".parse(), Ok(LabelMatch::Equals("zone".to_string(), "eu".to_string())));
+        assert_eq!("gpu!=true".parse(), Ok(LabelMatch::NotEquals("gpu".to_string(), "true".to_string())));
+        assert!("zone".parse::<LabelMatch>().is_err());
+        assert!("=eu".parse::<LabelMatch>().is_err());
+        assert_eq!(PlacementSpec::parse_matches("zone=eu,,gpu!=true").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn targets_parse() {
+        assert_eq!("auto".parse(), Ok(DeployTarget::Placement(PlacementSpec::default())));
+        let peer = PeerId::random();
+        assert_eq!(peer.to_base58().parse(), Ok(DeployTarget::Peer(peer)));
+        assert_eq!(
+            "zone=eu".parse(),
+            Ok(DeployTarget::Placement(spec("zone=eu", "", None, 1)))
+        );
+        assert!("not a peer".parse::<DeployTarget>().is_err());
+    }
+
+    #[test]
+    fn constraints_filter_nodes() {
+        let eu = node(&[("zone", "eu")], 1);
+        let eu_gpu = node(&[("zone", "eu"), ("gpu", "true")], 8);
+        let us = node(&[("zone", "us")], 8);
+        let chosen = spec("zone=eu,gpu!=true", "", None, 1).place(vec![eu.clone(), eu_gpu, us]).unwrap();
+        assert_eq!(chosen, vec![eu.0]);
+    }
+
+    #[test]
+    fn saturated_nodes_are_skipped() {
+        let saturated = node(&[], 8);
+        let saturated = (saturated.0, LoadReport { cpu: 0.95, ..saturated.1 });
+        let free = node(&[], 1);
+        assert_eq!(spec("", "", None, 1).place(vec![saturated, free.clone()]).unwrap(), vec![free.0]);
+    }
+
+    #[test]
+    fn preferences_come_before_capacity() {
+        let preferred = node(&[("zone", "eu"), ("ssd", "true")], 1);
+        let partly_preferred = node(&[("zone", "eu")], 4);
+        let free = node(&[], 8);
+        let chosen = spec("", "zone=eu,ssd=true", None, 3)
+            .place(vec![free.clone(), partly_preferred.clone(), preferred.clone()])
+            .unwrap();
+        assert_eq!(chosen, vec![preferred.0, partly_preferred.0, free.0]);
+    }
+
+    #[test]
+    fn free_capacity_breaks_ties() {
+        let busy = node(&[], 1);
+        let free = node(&[], 8);
+        let free_memory = (PeerId::random(), LoadReport { free_slots: 1, free_memory_mb: 1024, ..LoadReport::default() });
+        let chosen = spec("", "", None, 3).place(vec![busy.clone(), free_memory.clone(), free.clone()]).unwrap();
+        assert_eq!(chosen, vec![free.0, free_memory.0, busy.0]);
+    }
+
+    #[test]
+    fn spread_chooses_distinct_values() {
+        let eu = node(&[("zone", "eu")], 8);
+        let eu_other = node(&[("zone", "eu")], 4);
+        let us = node(&[("zone", "us")], 1);
+        let unlabeled = node(&[], 1);
+        let chosen = spec("", "", Some("zone"), 3).place(vec![eu.clone(), eu_other, us.clone(), unlabeled.clone()]).unwrap();
+        assert_eq!(chosen.len(), 3);
+        assert_eq!(chosen[0], eu.0);
+        assert!(chosen.contains(&us.0) && chosen.contains(&unlabeled.0));
+    }
+
+    #[test]
+    fn too_few_nodes_fail() {
+        let eu = node(&[("zone", "eu")], 8);
+        let eu_other = node(&[("zone", "eu")], 4);
+        assert!(matches!(spec("", "", Some("zone"), 2).place(vec![eu.clone(), eu_other]), Err(FaasError::Unavailable(_))));
+        assert!(matches!(spec("zone=us", "", None, 1).place(vec![eu]), Err(FaasError::Unavailable(_))));
+        assert!(spec("", "", None, 1).place(Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use libp2p::PeerId;
+use serde::Deserialize;
+
+use crate::error::FaasError;
+
+/// Decision taken for peers not listed in the policy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PolicyAction {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// Policy file as written by the operator, with peer IDs as strings.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    default: PolicyAction,
+    #[serde(default)]
+    allow_peers: Vec<String>,
+    #[serde(default)]
+    deny_peers: Vec<String>,
+    #[serde(default)]
+    functions: HashMap<String, FunctionRuleFile>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FunctionRuleFile {
+    allow_peers: Option<Vec<String>>,
+    #[serde(default)]
+    deny_peers: Vec<String>,
+}
+
+/// Rule restricting the peers allowed to invoke a function.
+#[derive(Debug, Default)]
+struct FunctionRule {
+    /// Only these peers can invoke the function, when set.
+    allow: Option<HashSet<PeerId>>,
+    deny: HashSet<PeerId>,
+}
+
+/// Policy deciding which peers can run functions on this node through P2P requests.
+///
+/// A peer denied globally or for the function is always rejected. Otherwise, a function
+/// with an allowlist only accepts the peers in it, and the remaining functions accept the
+/// globally allowed peers and, depending on the default action, every other peer.
+#[derive(Debug, Default)]
+pub(crate) struct InboundPolicy {
+    default: PolicyAction,
+    allow: HashSet<PeerId>,
+    deny: HashSet<PeerId>,
+    functions: HashMap<String, FunctionRule>,
+}
+
+impl InboundPolicy {
+    /// Policy accepting every peer, used when no policy file is configured.
+    pub(crate) fn allow_all() -> Self {
+        Self::default()
+    }
+
+    /// Read the policy from a JSON file.
+    pub(crate) fn from_file(path: &Path) -> Result<Self, FaasError> {
+        let content = fs::read_to_string(path)?;
+        let file: PolicyFile = serde_json::from_str(&content)
+            .map_err(|e| FaasError::BadInput(format!("Invalid inbound policy in {:?}: {}", path, e)))?;
+        Self::try_from(file)
+    }
+
+    /// Check whether the peer can invoke the function.
+    pub(crate) fn check(&self, peer: &PeerId, function_name: &str) -> Result<(), FaasError> {
+        let forbidden = || Err(FaasError::Forbidden(format!("Peer {} is not allowed to invoke function {}", peer, function_name)));
+        if self.deny.contains(peer) {
+            return forbidden();
+        }
+        if let Some(rule) = self.functions.get(function_name) {
+            if rule.deny.contains(peer) {
+                return forbidden();
+            }
+            if let Some(allow) = &rule.allow {
+                return if allow.contains(peer) { Ok(()) } else { forbidden() };
+            }
+        }
+        if self.allow.contains(peer) || self.default == PolicyAction::Allow {
+            Ok(())
+        } else {
+            forbidden()
+        }
+    }
+}
+
+impl TryFrom<PolicyFile> for InboundPolicy {
+    type Error = FaasError;
+
+    fn try_from(file: PolicyFile) -> Result<Self, FaasError> {
+        let functions = file.functions
+            .into_iter()
+            .map(|(name, rule)| {
+                let allow = rule.allow_peers.map(|peers| parse_peers(&peers)).transpose()?;
+                let deny = parse_peers(&rule.deny_peers)?;
+                Ok((name, FunctionRule { allow, deny }))
+            })
+            .collect::<Result<_, FaasError>>()?;
+        Ok(Self {
+            default: file.default,
+            allow: parse_peers(&file.allow_peers)?,
+            deny: parse_peers(&file.deny_peers)?,
+            functions,
+        })
+    }
+}
+
+fn parse_peers(peers: &[String]) -> Result<HashSet<PeerId>, FaasError> {
+    peers
+        .iter()
+        .map(|peer| {
+            peer.parse::<PeerId>()
+                .map_err(|e| FaasError::BadInput(format!("Invalid peer ID {} in inbound policy: {}", peer, e)))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(json: &str) -> InboundPolicy {
+        let file: PolicyFile = serde_json::from_str(json).unwrap();
+        InboundPolicy::try_from(file).unwrap()
+    }
+
+    #[test]
+    fn allow_all_accepts_every_peer() {
+        let policy = InboundPolicy::allow_all();
+        assert!(policy.check(&PeerId::random(), "echo").is_ok());
+        assert!(policy.check(&PeerId::random(), "").is_ok());
+    }
+
+    #[test]
+    fn global_rules_apply_to_every_function() {
+        let (allowed, denied, other) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let policy = policy(&format!(r#"{{"default": "deny", "allow_peers": ["{}"], "deny_peers": ["{}"]}}"#, allowed, denied));
+        assert!(policy.check(&allowed, "echo").is_ok());
+        assert!(matches!(policy.check(&denied, "echo"), Err(FaasError::Forbidden(_))));
+        assert!(matches!(policy.check(&other, "echo"), Err(FaasError::Forbidden(_))));
+    }
+
+    #[test]
+    fn global_deny_overrides_default_allow() {
+        let denied = PeerId::random();
+        let policy = policy(&format!(r#"{{"deny_peers": ["{}"]}}"#, denied));
+        assert!(policy.check(&denied, "echo").is_err());
+        assert!(policy.check(&PeerId::random(), "echo").is_ok());
+    }
+
+    #[test]
+    fn function_rules_restrict_their_function_only() {
+        let (allowed, denied, other) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let policy = policy(&format!(
+            r#"{{"allow_peers": ["{}"], "functions": {{"private": {{"allow_peers": ["{}"]}}, "echo": {{"deny_peers": ["{}"]}}}}}}"#,
+            other, allowed, denied,
+        ));
+        assert!(policy.check(&allowed, "private").is_ok());
+        // The function allowlist replaces the global one.
+        assert!(policy.check(&other, "private").is_err());
+        assert!(policy.check(&denied, "echo").is_err());
+        assert!(policy.check(&denied, "other").is_ok());
+    }
+
+    #[test]
+    fn global_deny_overrides_function_allowlist() {
+        let peer = PeerId::random();
+        let policy = policy(&format!(r#"{{"deny_peers": ["{0}"], "functions": {{"echo": {{"allow_peers": ["{0}"]}}}}}}"#, peer));
+        assert!(policy.check(&peer, "echo").is_err());
+    }
+
+    #[test]
+    fn deploy_requests_without_function_name_follow_global_rules() {
+        let (allowed, other) = (PeerId::random(), PeerId::random());
+        let policy = policy(&format!(
+            r#"{{"default": "deny", "allow_peers": ["{}"], "functions": {{"echo": {{"allow_peers": ["{}"]}}}}}}"#,
+            allowed, other,
+        ));
+        assert!(policy.check(&allowed, "").is_ok());
+        assert!(policy.check(&other, "").is_err());
+    }
+
+    #[test]
+    fn invalid_policies_are_rejected() {
+        assert!(serde_json::from_str::<PolicyFile>(r#"{"allow": []}"#).is_err());
+        let file: PolicyFile = serde_json::from_str(r#"{"allow_peers": ["not a peer"]}"#).unwrap();
+        assert!(matches!(InboundPolicy::try_from(file), Err(FaasError::BadInput(_))));
+    }
+}
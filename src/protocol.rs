@@ -0,0 +1,508 @@
+use std::collections::{HashMap, TryReserveError};
+use std::convert::Infallible;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::request_response;
+use libp2p::StreamProtocol;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::model::CompressionStats;
+
+/// First version of the protocol, with positional messages. Kept for nodes not upgraded yet.
+pub(crate) const PROTOCOL_V1: StreamProtocol = StreamProtocol::new("/function-request/1");
+/// Current version of the protocol, with named and optional fields.
+pub(crate) const PROTOCOL_V2: StreamProtocol = StreamProtocol::new("/function-request/2");
+/// Current version of the protocol with compressed messages.
+pub(crate) const PROTOCOL_V2_ZSTD: StreamProtocol = StreamProtocol::new("/function-request/2/zstd");
+pub(crate) const PROTOCOL_V2_GZIP: StreamProtocol = StreamProtocol::new("/function-request/2/gzip");
+
+/// Max request size in bytes
+const REQUEST_SIZE_MAXIMUM: u64 = 1024 * 1024;
+/// Max response size in bytes
+const RESPONSE_SIZE_MAXIMUM: u64 = 10 * 1024 * 1024;
+
+/// Request to execute a function on a provider.
+///
+/// New fields must be optional so that nodes running an older build can still decode the message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FunctionRequest {
+    /// Function name followed by the path and query to request.
+    pub function: String,
+    pub method: String,
+    #[serde(default)]
+    pub body: Option<Vec<u8>>,
+    /// Identifier to correlate the logs of the caller and the provider.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// HTTP headers to pass to the function.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    /// Time left to the caller, in milliseconds, after which the provider can give up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+}
+
+impl FunctionRequest {
+    pub(crate) fn timeout(&self) -> Option<Duration> {
+        self.timeout_ms.map(Duration::from_millis)
+    }
+}
+
+/// Response of the provider with the result of the function.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FunctionResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+    /// Kind of the error, when the provider failed to run the function.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<String>,
+    /// HTTP headers returned by the function.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+}
+
+impl FunctionResponse {
+    pub(crate) fn new(status: u16, body: Vec<u8>) -> Self {
+        Self {
+            status,
+            body,
+            error_kind: None,
+            headers: HashMap::new(),
+        }
+    }
+}
+
+/// Protocol to cancel a function request the caller gave up on.
+pub(crate) const CANCEL_PROTOCOL: StreamProtocol = StreamProtocol::new("/function-cancel/1");
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CancelRequest {
+    pub request_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct CancelResponse;
+
+/// Algorithm to compress the messages of the protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Compression {
+    Zstd,
+    Gzip,
+}
+
+impl Compression {
+    fn of_protocol(protocol: &StreamProtocol) -> Option<Self> {
+        if *protocol == PROTOCOL_V2_ZSTD {
+            Some(Compression::Zstd)
+        } else if *protocol == PROTOCOL_V2_GZIP {
+            Some(Compression::Gzip)
+        } else {
+            None
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::Zstd => zstd::stream::encode_all(data, 0),
+            Compression::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Decompress the data, failing when it expands beyond the limit.
+    fn decompress(self, data: &[u8], limit: u64) -> io::Result<Vec<u8>> {
+        let decoder: Box<dyn Read> = match self {
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(data)?),
+            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(data)),
+        };
+        let mut decompressed = Vec::new();
+        decoder.take(limit + 1).read_to_end(&mut decompressed)?;
+        if decompressed.len() as u64 > limit {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Decompressed message exceeds the limit of {} bytes", limit)));
+        }
+        Ok(decompressed)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "zstd" => Ok(Compression::Zstd),
+            "gzip" => Ok(Compression::Gzip),
+            _ => Err(format!("Unknown compression {}, expected zstd or gzip", s)),
+        }
+    }
+}
+
+/// Counters of the bytes saved by compression, shared by the codecs of all the connections.
+#[derive(Debug, Default)]
+pub(crate) struct CompressionCounters {
+    messages_compressed: AtomicU64,
+    bytes_saved_sent: AtomicU64,
+    bytes_saved_received: AtomicU64,
+}
+
+impl CompressionCounters {
+    pub(crate) fn stats(&self) -> CompressionStats {
+        CompressionStats {
+            messages_compressed: self.messages_compressed.load(Ordering::Relaxed),
+            bytes_saved_sent: self.bytes_saved_sent.load(Ordering::Relaxed),
+            bytes_saved_received: self.bytes_saved_received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Messages of the first version of the protocol.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FunctionRequestV1(String, String, Option<Vec<u8>>);
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct FunctionResponseV1(u16, Vec<u8>);
+
+impl From<FunctionRequestV1> for FunctionRequest {
+    fn from(FunctionRequestV1(function, method, body): FunctionRequestV1) -> Self {
+        Self {
+            function,
+            method,
+            body,
+            request_id: None,
+            headers: HashMap::new(),
+            timeout_ms: None,
+        }
+    }
+}
+
+impl From<FunctionRequest> for FunctionRequestV1 {
+    fn from(request: FunctionRequest) -> Self {
+        Self(request.function, request.method, request.body)
+    }
+}
+
+impl From<FunctionResponseV1> for FunctionResponse {
+    fn from(FunctionResponseV1(status, body): FunctionResponseV1) -> Self {
+        Self::new(status, body)
+    }
+}
+
+impl From<FunctionResponse> for FunctionResponseV1 {
+    fn from(response: FunctionResponse) -> Self {
+        Self(response.status, response.body)
+    }
+}
+
+/// CBOR codec speaking both versions of the protocol, the version being the one negotiated
+/// with the peer. Messages are converted from and to the first version when needed.
+///
+/// On the compressed variants of the current version, messages start with a flag byte telling
+/// whether the rest is compressed, which is only done for messages above the threshold.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FunctionCodec {
+    /// Minimum size in bytes of the messages to compress.
+    compression_threshold: usize,
+    counters: Arc<CompressionCounters>,
+}
+
+impl FunctionCodec {
+    pub(crate) fn new(compression_threshold: usize, counters: Arc<CompressionCounters>) -> Self {
+        Self {
+            compression_threshold,
+            counters,
+        }
+    }
+
+    async fn read_message<T, M, V1>(&self, protocol: &StreamProtocol, io: &mut T, limit: u64) -> io::Result<M>
+    where
+        T: AsyncRead + Unpin + Send,
+        M: DeserializeOwned + From<V1>,
+        V1: DeserializeOwned,
+    {
+        if *protocol == PROTOCOL_V1 {
+            let data = read_to_end(io, limit).await?;
+            return decode::<V1>(&data).map(Into::into);
+        }
+        let Some(compression) = Compression::of_protocol(protocol) else {
+            return decode(&read_to_end(io, limit).await?);
+        };
+        // Flag byte followed by the message.
+        let data = read_to_end(io, limit + 1).await?;
+        match data.split_first() {
+            Some((&UNCOMPRESSED, message)) => decode(message),
+            Some((&COMPRESSED, message)) => {
+                let decompressed = compression.decompress(message, limit)?;
+                self.counters.messages_compressed.fetch_add(1, Ordering::Relaxed);
+                self.counters.bytes_saved_received.fetch_add(decompressed.len().saturating_sub(message.len()) as u64, Ordering::Relaxed);
+                decode(&decompressed)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid compression flag")),
+        }
+    }
+
+    async fn write_message<T, M, V1>(&self, protocol: &StreamProtocol, io: &mut T, message: M) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+        M: Serialize + Into<V1>,
+        V1: Serialize,
+    {
+        if *protocol == PROTOCOL_V1 {
+            return io.write_all(&encode(&message.into())?).await;
+        }
+        let data = encode(&message)?;
+        let Some(compression) = Compression::of_protocol(protocol) else {
+            return io.write_all(&data).await;
+        };
+        if data.len() >= self.compression_threshold {
+            let compressed = compression.compress(&data)?;
+            // Incompressible messages are sent as they are.
+            if compressed.len() < data.len() {
+                self.counters.messages_compressed.fetch_add(1, Ordering::Relaxed);
+                self.counters.bytes_saved_sent.fetch_add((data.len() - compressed.len()) as u64, Ordering::Relaxed);
+                io.write_all(&[COMPRESSED]).await?;
+                return io.write_all(&compressed).await;
+            }
+        }
+        io.write_all(&[UNCOMPRESSED]).await?;
+        io.write_all(&data).await
+    }
+}
+
+const UNCOMPRESSED: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+#[async_trait]
+impl request_response::Codec for FunctionCodec {
+    type Protocol = StreamProtocol;
+    type Request = FunctionRequest;
+    type Response = FunctionResponse;
+
+    async fn read_request<T>(&mut self, protocol: &StreamProtocol, io: &mut T) -> io::Result<FunctionRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        self.read_message::<_, _, FunctionRequestV1>(protocol, io, REQUEST_SIZE_MAXIMUM).await
+    }
+
+    async fn read_response<T>(&mut self, protocol: &StreamProtocol, io: &mut T) -> io::Result<FunctionResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        self.read_message::<_, _, FunctionResponseV1>(protocol, io, RESPONSE_SIZE_MAXIMUM).await
+    }
+
+    async fn write_request<T>(&mut self, protocol: &StreamProtocol, io: &mut T, request: FunctionRequest) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        self.write_message::<_, _, FunctionRequestV1>(protocol, io, request).await
+    }
+
+    async fn write_response<T>(&mut self, protocol: &StreamProtocol, io: &mut T, response: FunctionResponse) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        self.write_message::<_, _, FunctionResponseV1>(protocol, io, response).await
+    }
+}
+
+async fn read_to_end<T>(io: &mut T, limit: u64) -> io::Result<Vec<u8>>
+where
+    T: AsyncRead + Unpin + Send,
+{
+    let mut data = Vec::new();
+    io.take(limit).read_to_end(&mut data).await?;
+    Ok(data)
+}
+
+fn decode<M: DeserializeOwned>(data: &[u8]) -> io::Result<M> {
+    cbor4ii::serde::from_slice(data)
+        .map_err(|e: cbor4ii::serde::DecodeError<Infallible>| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+fn encode<M: Serialize>(message: &M) -> io::Result<Vec<u8>> {
+    cbor4ii::serde::to_vec(Vec::new(), message)
+        .map_err(|e: cbor4ii::serde::EncodeError<TryReserveError>| io::Error::other(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+    use request_response::Codec;
+
+    fn request() -> FunctionRequest {
+        FunctionRequest {
+            function: "echo/path?query=1".to_string(),
+            method: "POST".to_string(),
+            body: Some(b"hello".to_vec()),
+            request_id: Some("id".to_string()),
+            headers: HashMap::from([("Content-Type".to_string(), "text/plain".to_string())]),
+            timeout_ms: Some(1000),
+        }
+    }
+
+    fn response() -> FunctionResponse {
+        FunctionResponse {
+            status: 503,
+            body: b"busy".to_vec(),
+            error_kind: Some("busy".to_string()),
+            headers: HashMap::from([("X-Node".to_string(), "a".to_string())]),
+        }
+    }
+
+    async fn round_trip_request(codec: &mut FunctionCodec, protocol: &StreamProtocol, request: FunctionRequest) -> io::Result<FunctionRequest> {
+        let mut io = Cursor::new(Vec::new());
+        codec.write_request(protocol, &mut io, request).await?;
+        codec.read_request(protocol, &mut Cursor::new(io.into_inner())).await
+    }
+
+    async fn round_trip_response(codec: &mut FunctionCodec, protocol: &StreamProtocol, response: FunctionResponse) -> io::Result<FunctionResponse> {
+        let mut io = Cursor::new(Vec::new());
+        codec.write_response(protocol, &mut io, response).await?;
+        codec.read_response(protocol, &mut Cursor::new(io.into_inner())).await
+    }
+
+    #[tokio::test]
+    async fn v2_keeps_every_field() {
+        let mut codec = FunctionCodec::default();
+        assert_eq!(round_trip_request(&mut codec, &PROTOCOL_V2, request()).await.unwrap(), request());
+        assert_eq!(round_trip_response(&mut codec, &PROTOCOL_V2, response()).await.unwrap(), response());
+    }
+
+    #[tokio::test]
+    async fn v1_keeps_the_positional_fields() {
+        let mut codec = FunctionCodec::default();
+        let decoded = round_trip_request(&mut codec, &PROTOCOL_V1, request()).await.unwrap();
+        assert_eq!(decoded, FunctionRequest {
+            request_id: None,
+            headers: HashMap::new(),
+            timeout_ms: None,
+            ..request()
+        });
+        let decoded = round_trip_response(&mut codec, &PROTOCOL_V1, response()).await.unwrap();
+        assert_eq!(decoded, FunctionResponse::new(503, b"busy".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn v1_messages_are_positional() {
+        let mut codec = FunctionCodec::default();
+        let data = encode(&FunctionRequestV1("echo".to_string(), "GET".to_string(), None)).unwrap();
+        let decoded = codec.read_request(&PROTOCOL_V1, &mut Cursor::new(data)).await.unwrap();
+        assert_eq!((decoded.function.as_str(), decoded.method.as_str(), decoded.body), ("echo", "GET", None));
+    }
+
+    #[tokio::test]
+    async fn v2_decodes_messages_without_optional_fields() {
+        #[derive(Serialize)]
+        struct OldRequest {
+            function: String,
+            method: String,
+        }
+        let mut codec = FunctionCodec::default();
+        let data = encode(&OldRequest { function: "echo".to_string(), method: "GET".to_string() }).unwrap();
+        let decoded = codec.read_request(&PROTOCOL_V2, &mut Cursor::new(data)).await.unwrap();
+        assert_eq!(decoded.body, None);
+        assert_eq!(decoded.timeout(), None);
+        assert!(decoded.headers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn oversized_requests_are_rejected() {
+        let mut codec = FunctionCodec::default();
+        let request = FunctionRequest {
+            body: Some(vec![0; REQUEST_SIZE_MAXIMUM as usize]),
+            ..request()
+        };
+        assert!(round_trip_request(&mut codec, &PROTOCOL_V2, request).await.is_err());
+    }
+
+    fn compressing_codec() -> FunctionCodec {
+        FunctionCodec::new(64, Arc::new(CompressionCounters::default()))
+    }
+
+    #[tokio::test]
+    async fn compressed_variants_keep_every_field() {
+        for protocol in [PROTOCOL_V2_ZSTD, PROTOCOL_V2_GZIP] {
+            let mut codec = compressing_codec();
+            let request = FunctionRequest {
+                body: Some(vec![b'a'; 4096]),
+                ..request()
+            };
+            assert_eq!(round_trip_request(&mut codec, &protocol, request.clone()).await.unwrap(), request);
+            assert_eq!(round_trip_response(&mut codec, &protocol, response()).await.unwrap(), response());
+        }
+    }
+
+    #[tokio::test]
+    async fn only_messages_above_the_threshold_are_compressed() {
+        let mut codec = compressing_codec();
+        let mut io = Cursor::new(Vec::new());
+        codec.write_response(&PROTOCOL_V2_ZSTD, &mut io, FunctionResponse::new(200, Vec::new())).await.unwrap();
+        assert_eq!(io.into_inner()[0], UNCOMPRESSED);
+
+        let mut io = Cursor::new(Vec::new());
+        codec.write_response(&PROTOCOL_V2_ZSTD, &mut io, FunctionResponse::new(200, vec![0; 4096])).await.unwrap();
+        assert_eq!(io.into_inner()[0], COMPRESSED);
+        let stats = codec.counters.stats();
+        assert_eq!(stats.messages_compressed, 1);
+        assert!(stats.bytes_saved_sent > 0);
+    }
+
+    #[test]
+    fn decompression_stops_at_the_limit() {
+        for compression in [Compression::Zstd, Compression::Gzip] {
+            let compressed = compression.compress(&[0; 4096]).unwrap();
+            assert_eq!(compression.decompress(&compressed, 4096).unwrap().len(), 4096);
+            let error = compression.decompress(&compressed, 4095).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn compressed_requests_expanding_beyond_the_limit_are_rejected() {
+        let mut codec = compressing_codec();
+        let request = FunctionRequest {
+            body: Some(vec![0; REQUEST_SIZE_MAXIMUM as usize]),
+            ..request()
+        };
+        // The compressed message fits in the limit, not the decompressed one.
+        let mut io = Cursor::new(Vec::new());
+        codec.write_request(&PROTOCOL_V2_GZIP, &mut io, request).await.unwrap();
+        let data = io.into_inner();
+        assert!((data.len() as u64) < REQUEST_SIZE_MAXIMUM);
+        assert!(codec.read_request(&PROTOCOL_V2_GZIP, &mut Cursor::new(data)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_compressed_frames_are_rejected() {
+        let mut codec = compressing_codec();
+        let mut data = vec![COMPRESSED];
+        data.extend(Compression::Zstd.compress(&encode(&request()).unwrap()).unwrap());
+        data.resize(REQUEST_SIZE_MAXIMUM as usize + 2, 0);
+        assert!(codec.read_request(&PROTOCOL_V2_ZSTD, &mut Cursor::new(data)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn invalid_compression_flags_are_rejected() {
+        let mut codec = compressing_codec();
+        let mut data = vec![2];
+        data.extend(encode(&request()).unwrap());
+        let error = codec.read_request(&PROTOCOL_V2_GZIP, &mut Cursor::new(data)).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn compression_parses_from_its_name() {
+        assert_eq!("zstd".parse::<Compression>(), Ok(Compression::Zstd));
+        assert_eq!("gzip".parse::<Compression>(), Ok(Compression::Gzip));
+        assert!("brotli".parse::<Compression>().is_err());
+    }
+}
@@ -0,0 +1,233 @@
+use std::io;
+
+use bytes::Bytes;
+use futures::io::WriteHalf;
+use futures::prelude::*;
+use futures::stream::BoxStream;
+use libp2p::{Stream, StreamProtocol};
+use tokio::time::{timeout_at, Instant};
+
+use crate::error::FaasError;
+use crate::protocol::{FunctionRequest, FunctionResponse};
+
+use log::warn;
+
+/// Protocol to invoke a function streaming the request and response bodies.
+///
+/// The caller sends a request header, a `FunctionRequest` without body, followed by the body
+/// in chunks. The provider answers with a response header, a `FunctionResponse` without body,
+/// followed by the body in chunks. Every message is a frame prefixed by its length as a
+/// big-endian u32, and an empty frame ends a body.
+pub(crate) const STREAM_PROTOCOL: StreamProtocol = StreamProtocol::new("/function-stream/1");
+
+/// Body of a request or response, in chunks.
+pub(crate) type BodyStream = BoxStream<'static, Result<Bytes, FaasError>>;
+
+/// Max header size in bytes
+const HEADER_SIZE_MAXIMUM: usize = 64 * 1024;
+/// Max chunk size in bytes, larger chunks are split.
+const CHUNK_SIZE_MAXIMUM: usize = 64 * 1024;
+
+/// Send the request over the stream and return the response of the provider.
+/// The request body is sent while the response is read, so the function can answer before the end.
+pub(crate) async fn send_request(stream: Stream, request: FunctionRequest, body: BodyStream) -> Result<(FunctionResponse, BodyStream), FaasError> {
+    let (mut reader, mut writer) = stream.split();
+    write_header(&mut writer, &request).await?;
+    tokio::spawn(async move {
+        if let Err(e) = write_body(&mut writer, body).await {
+            warn!("Failed to stream request body: {:?}", e);
+        }
+    });
+    let response: FunctionResponse = read_header(&mut reader).await?;
+    Ok((response, read_body(reader)))
+}
+
+/// Provider side of a stream, answering a request.
+pub(crate) struct ResponseWriter {
+    writer: WriteHalf<Stream>,
+}
+
+impl ResponseWriter {
+    pub(crate) async fn respond(mut self, response: FunctionResponse, body: BodyStream) -> Result<(), FaasError> {
+        write_header(&mut self.writer, &response).await?;
+        write_body(&mut self.writer, body).await
+    }
+}
+
+/// Read a request from the stream, returning its header, its body and the writer of the response.
+pub(crate) async fn receive_request(stream: Stream) -> Result<(FunctionRequest, BodyStream, ResponseWriter), FaasError> {
+    let (mut reader, writer) = stream.split();
+    let request: FunctionRequest = read_header(&mut reader).await?;
+    Ok((request, read_body(reader), ResponseWriter { writer }))
+}
+
+async fn write_header<W: AsyncWrite + Unpin, M: serde::Serialize>(writer: &mut W, header: &M) -> Result<(), FaasError> {
+    let data = cbor4ii::serde::to_vec(Vec::new(), header)
+        .map_err(|e| FaasError::Internal(format!("Failed to encode stream header: {}", e)))?;
+    write_frame(writer, &data).await.map_err(stream_error)
+}
+
+async fn read_header<R: AsyncRead + Unpin, M: serde::de::DeserializeOwned>(reader: &mut R) -> Result<M, FaasError> {
+    let data = read_frame(reader, HEADER_SIZE_MAXIMUM).await.map_err(stream_error)?;
+    cbor4ii::serde::from_slice(&data)
+        .map_err(|e| FaasError::BadInput(format!("Invalid stream header: {}", e)))
+}
+
+async fn write_body<W: AsyncWrite + Unpin>(writer: &mut W, mut body: BodyStream) -> Result<(), FaasError> {
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        for part in chunk.chunks(CHUNK_SIZE_MAXIMUM).filter(|part| !part.is_empty()) {
+            write_frame(writer, part).await.map_err(stream_error)?;
+        }
+    }
+    write_frame(writer, &[]).await.map_err(stream_error)?;
+    writer.close().await.map_err(stream_error)
+}
+
+fn read_body<R: AsyncRead + Unpin + Send + 'static>(reader: R) -> BodyStream {
+    stream::try_unfold(reader, |mut reader| async move {
+        let chunk = read_frame(&mut reader, CHUNK_SIZE_MAXIMUM).await.map_err(stream_error)?;
+        if chunk.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some((Bytes::from(chunk), reader)))
+        }
+    })
+    .boxed()
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    writer.write_all(data).await?;
+    writer.flush().await
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R, limit: usize) -> io::Result<Vec<u8>> {
+    let mut length = [0u8; 4];
+    reader.read_exact(&mut length).await?;
+    let length = u32::from_be_bytes(length) as usize;
+    if length > limit {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Frame of {} bytes exceeds the limit of {} bytes", length, limit)));
+    }
+    let mut data = vec![0u8; length];
+    reader.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+/// End the body with a timeout error once the deadline passes, dropping the rest of it.
+pub(crate) fn with_deadline(body: BodyStream, deadline: Instant) -> BodyStream {
+    stream::unfold(Some(body), move |body| async move {
+        let mut body = body?;
+        match timeout_at(deadline, body.next()).await {
+            Ok(Some(chunk)) => Some((chunk, Some(body))),
+            Ok(None) => None,
+            Err(_) => Some((Err(FaasError::Timeout("Function did not finish streaming its response in time".to_string())), None)),
+        }
+    })
+    .boxed()
+}
+
+fn stream_error(e: io::Error) -> FaasError {
+    FaasError::Network(format!("Function stream failed: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::io::Cursor;
+    use std::time::Duration;
+
+    fn body(chunks: Vec<Vec<u8>>) -> BodyStream {
+        stream::iter(chunks.into_iter().map(|chunk| Ok(Bytes::from(chunk)))).boxed()
+    }
+
+    #[tokio::test]
+    async fn frames_are_prefixed_by_their_length() {
+        let mut io = Cursor::new(Vec::new());
+        write_frame(&mut io, b"abc").await.unwrap();
+        write_frame(&mut io, &[]).await.unwrap();
+        assert_eq!(io.get_ref(), &[0, 0, 0, 3, b'a', b'b', b'c', 0, 0, 0, 0]);
+
+        let mut io = Cursor::new(io.into_inner());
+        assert_eq!(read_frame(&mut io, 3).await.unwrap(), b"abc");
+        assert!(read_frame(&mut io, 3).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn frames_above_the_limit_are_rejected() {
+        let mut io = Cursor::new(Vec::new());
+        write_frame(&mut io, b"abcd").await.unwrap();
+        let error = read_frame(&mut Cursor::new(io.into_inner()), 3).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn truncated_frames_are_rejected() {
+        let error = read_frame(&mut Cursor::new(vec![0, 0, 0, 3, b'a']), 3).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn headers_and_bodies_round_trip() {
+        let request = FunctionRequest {
+            function: "echo".to_string(),
+            method: "POST".to_string(),
+            body: None,
+            request_id: Some("id".to_string()),
+            headers: Default::default(),
+            timeout_ms: None,
+        };
+        let mut io = Cursor::new(Vec::new());
+        write_header(&mut io, &request).await.unwrap();
+        write_body(&mut io, body(vec![b"hello ".to_vec(), Vec::new(), b"world".to_vec()])).await.unwrap();
+
+        let mut io = Cursor::new(io.into_inner());
+        let decoded: FunctionRequest = read_header(&mut io).await.unwrap();
+        assert_eq!(decoded, request);
+        // Empty chunks are skipped, as an empty frame ends the body.
+        let chunks: Vec<Bytes> = read_body(io).try_collect().await.unwrap();
+        assert_eq!(chunks, vec![Bytes::from_static(b"hello "), Bytes::from_static(b"world")]);
+    }
+
+    #[tokio::test]
+    async fn large_chunks_are_split() {
+        let mut io = Cursor::new(Vec::new());
+        write_body(&mut io, body(vec![vec![1; CHUNK_SIZE_MAXIMUM * 2 + 1]])).await.unwrap();
+        let chunks: Vec<Bytes> = read_body(Cursor::new(io.into_inner())).try_collect().await.unwrap();
+        assert_eq!(chunks.iter().map(Bytes::len).collect::<Vec<_>>(), vec![CHUNK_SIZE_MAXIMUM, CHUNK_SIZE_MAXIMUM, 1]);
+    }
+
+    #[tokio::test]
+    async fn bodies_without_end_frame_fail() {
+        let mut io = Cursor::new(Vec::new());
+        write_frame(&mut io, b"partial").await.unwrap();
+        let chunks: Result<Vec<Bytes>, FaasError> = read_body(Cursor::new(io.into_inner())).try_collect().await;
+        assert!(matches!(chunks, Err(FaasError::Network(_))));
+    }
+
+    #[tokio::test]
+    async fn bodies_end_with_a_timeout_past_the_deadline() {
+        let body = stream::iter([Ok(Bytes::from_static(b"first"))]).chain(stream::pending()).boxed();
+        let chunks: Vec<Result<Bytes, FaasError>> = with_deadline(body, Instant::now() + Duration::from_millis(50)).collect().await;
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].as_ref().unwrap(), &Bytes::from_static(b"first"));
+        assert!(matches!(chunks[1], Err(FaasError::Timeout(_))));
+    }
+
+    #[tokio::test]
+    async fn bodies_finishing_before_the_deadline_are_unchanged() {
+        let chunks: Vec<Bytes> = with_deadline(body(vec![b"a".to_vec(), b"b".to_vec()]), Instant::now() + Duration::from_secs(10))
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks, vec![Bytes::from_static(b"a"), Bytes::from_static(b"b")]);
+    }
+
+    #[tokio::test]
+    async fn invalid_headers_are_rejected() {
+        let mut io = Cursor::new(Vec::new());
+        write_frame(&mut io, &[0xff]).await.unwrap();
+        let header: Result<FunctionResponse, FaasError> = read_header(&mut Cursor::new(io.into_inner())).await;
+        assert!(matches!(header, Err(FaasError::BadInput(_))));
+    }
+}
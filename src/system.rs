@@ -0,0 +1,32 @@
+use std::fs;
+use std::thread::available_parallelism;
+
+/// Load average of the last minute divided by the number of CPUs, 1.0 meaning all CPUs are busy.
+pub(crate) fn cpu_load() -> Option<f32> {
+    let loadavg = fs::read_to_string("/proc/loadavg").ok()?;
+    let load: f32 = loadavg.split_whitespace().next()?.parse().ok()?;
+    let cpus = available_parallelism().ok()?.get() as f32;
+    Some(load / cpus)
+}
+
+/// Fraction of the memory in use, from 0.0 to 1.0.
+pub(crate) fn memory_usage() -> Option<f32> {
+    let total = meminfo_kb("MemTotal:")? as f32;
+    let available = meminfo_kb("MemAvailable:")? as f32;
+    if total == 0.0 {
+        return None;
+    }
+    Some(1.0 - available / total)
+}
+
+/// Memory available for new processes, in MiB.
+pub(crate) fn available_memory_mb() -> Option<u64> {
+    Some(meminfo_kb("MemAvailable:")? / 1024)
+}
+
+/// Value of a /proc/meminfo field, in KiB.
+fn meminfo_kb(name: &str) -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with(name))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
@@ -0,0 +1,124 @@
+//! Nodes listening on QUIC, TCP or both, running the network layer in process.
+//! The node binary has no library target, so the modules of the network layer are included here.
+
+#![allow(dead_code)]
+
+#[path = "../src/data_structures.rs"]
+mod data_structures;
+#[path = "../src/error.rs"]
+mod error;
+#[path = "../src/model.rs"]
+mod model;
+#[path = "../src/network.rs"]
+mod network;
+#[path = "../src/package.rs"]
+mod package;
+#[path = "../src/protocol.rs"]
+mod protocol;
+#[path = "../src/streaming.rs"]
+mod streaming;
+
+use std::collections::HashSet;
+use std::net::{TcpListener, UdpSocket};
+use std::time::Duration;
+
+use futures::StreamExt;
+use libp2p::{Multiaddr, PeerId};
+use tokio::time::{sleep, timeout};
+
+use network::{Event, NetworkClient, NetworkConfig};
+use protocol::FunctionResponse;
+
+const TEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn quic_addr() -> Multiaddr {
+    let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    format!("/ip4/127.0.0.1/udp/{}/quic-v1", port).parse().unwrap()
+}
+
+fn tcp_addr() -> Multiaddr {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()
+}
+
+/// Start a node listening on the given addresses, answering every function request with its name.
+async fn start_node(name: &'static str, listen_addrs: &[Multiaddr]) -> (NetworkClient, PeerId) {
+    let config = NetworkConfig {
+        secret_key_seed: None,
+        provider_cache_ttl: Duration::from_secs(30),
+        enable_mdns: false,
+        bootstrap_interval: Duration::from_secs(300),
+        relays: Vec::new(),
+        enable_relay_server: false,
+        pre_shared_key: None,
+        load_report_ttl: Duration::from_secs(30),
+        max_connections: 16,
+        max_connections_per_peer: 4,
+        request_timeout: Duration::from_secs(10),
+        compression: None,
+        compression_threshold: 1024,
+    };
+    let (client, mut events, event_loop, peer_id) = network::new(config).await.unwrap();
+    tokio::spawn(event_loop.run());
+    for addr in listen_addrs {
+        client.start_listening(addr.clone()).await.unwrap();
+    }
+    tokio::spawn({
+        let client = client.clone();
+        async move {
+            while let Some(event) = events.next().await {
+                if let Event::InboundRequest { request, channel, .. } = event {
+                    let body = format!("{} from {}", request.function, name).into_bytes();
+                    client.respond_function(FunctionResponse::new(200, body), channel).await.unwrap();
+                }
+            }
+        }
+    });
+    (client, peer_id)
+}
+
+/// Look up the providers of the function until the expected ones are found.
+async fn wait_for_providers(client: &NetworkClient, function_name: &str, expected: HashSet<PeerId>) {
+    loop {
+        let providers = client.get_providers(function_name.to_string()).await;
+        if expected.is_subset(&providers) {
+            return;
+        }
+        client.invalidate_providers(function_name).await;
+        sleep(Duration::from_millis(200)).await;
+    }
+}
+
+async fn request(client: &NetworkClient, peer: PeerId) -> String {
+    let response = client
+        .request_function(peer, "echo".to_string(), "GET".to_string(), None, Some(Duration::from_secs(10)))
+        .await
+        .unwrap();
+    assert_eq!(response.status, 200);
+    String::from_utf8(response.body).unwrap()
+}
+
+#[tokio::test]
+async fn nodes_discover_and_invoke_functions_over_quic_and_tcp() {
+    let (quic, tcp) = (quic_addr(), tcp_addr());
+    let (both, both_id) = start_node("both", &[quic.clone(), tcp.clone()]).await;
+    let (quic_only, quic_only_id) = start_node("quic", &[quic_addr()]).await;
+    let (tcp_only, _) = start_node("tcp", &[tcp_addr()]).await;
+
+    timeout(TEST_TIMEOUT, async {
+        quic_only.dial(both_id, quic).await.unwrap();
+        tcp_only.dial(both_id, tcp).await.unwrap();
+        both.start_providing("echo".to_string()).await.unwrap();
+        quic_only.start_providing("echo".to_string()).await.unwrap();
+
+        // The node listening on TCP only finds the node listening on QUIC only through the DHT.
+        wait_for_providers(&tcp_only, "echo", HashSet::from([both_id, quic_only_id])).await;
+        wait_for_providers(&quic_only, "echo", HashSet::from([both_id])).await;
+
+        assert_eq!(request(&quic_only, both_id).await, "echo from both");
+        assert_eq!(request(&tcp_only, both_id).await, "echo from both");
+        assert_eq!(request(&tcp_only, quic_only_id).await, "echo from quic");
+    })
+    .await
+    .expect("nodes did not answer in time");
+}